@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use embedded_svc::mqtt::client::{MessageId, QoS};
+
+use crate::device::AckHandler;
+
+/// How long the outcome of a message is remembered after it completes, so it
+/// can still be queried, and so acknowledgements that arrive before their
+/// message is tracked are resolved.
+const COMPLETED_TTL: Duration = Duration::from_secs(300);
+/// The most outcomes remembered at once. The oldest are forgotten first.
+const MAX_COMPLETED: usize = 64;
+
+/// The outcome of publishing a QoS 1 message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The broker acknowledged the message.
+    Acknowledged,
+    /// The message expired and was deleted from the outbox before it was
+    /// acknowledged.
+    Deleted,
+}
+
+struct Completed {
+    delivery: Delivery,
+    at: Instant,
+    /// Whether the message was tracked when it completed. If not, its
+    /// acknowledgement arrived before `track()`.
+    tracked: bool,
+}
+
+#[derive(Default)]
+struct State {
+    pending: HashMap<MessageId, Option<Box<dyn AckHandler>>>,
+    completed: HashMap<MessageId, Completed>,
+}
+
+impl State {
+    fn completed(&self, id: MessageId) -> Option<Delivery> {
+        self.completed.get(&id).map(|completed| completed.delivery)
+    }
+
+    /// Forget outcomes older than `COMPLETED_TTL`, and the oldest beyond
+    /// `MAX_COMPLETED`.
+    fn prune(&mut self, now: Instant) {
+        self.completed
+            .retain(|_, completed| now.duration_since(completed.at) < COMPLETED_TTL);
+        while self.completed.len() > MAX_COMPLETED {
+            let oldest = self
+                .completed
+                .iter()
+                .min_by_key(|(_, completed)| completed.at)
+                .map(|(id, _)| *id);
+            if let Some(id) = oldest {
+                self.completed.remove(&id);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    completed: Condvar,
+}
+
+/// QoS 1 messages that have been published but not yet acknowledged, shared
+/// between a `Device` and its MQTT event handler.
+#[derive(Clone, Default)]
+pub(crate) struct Inflight(Arc<Shared>);
+
+impl Inflight {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start tracking a published message if it expects an acknowledgement.
+    /// Returns `id` for chaining.
+    pub(crate) fn track(&self, qos: QoS, id: MessageId) -> MessageId {
+        if qos == QoS::AtLeastOnce {
+            let mut state = self.state();
+            state.prune(Instant::now());
+            match state.completed.get_mut(&id) {
+                Some(completed) if !completed.tracked => completed.tracked = true,
+                // a reused ID
+                _ => {
+                    state.completed.remove(&id);
+                    state.pending.insert(id, None);
+                }
+            }
+        }
+
+        id
+    }
+
+    /// Record the outcome of message `id` and wake any waiters.
+    pub(crate) fn complete(&self, id: MessageId, delivery: Delivery) {
+        let handler = {
+            let mut state = self.state();
+            let now = Instant::now();
+            let pending = state.pending.remove(&id);
+            state.completed.insert(
+                id,
+                Completed {
+                    delivery,
+                    at: now,
+                    tracked: pending.is_some(),
+                },
+            );
+            state.prune(now);
+            pending.flatten()
+        };
+
+        self.0.completed.notify_all();
+        if let Some(handler) = handler {
+            handler(id, delivery);
+        }
+    }
+
+    /// Set the handler called when message `id` completes. If it has already
+    /// completed, `handler` is called immediately. Returns `false` if `id` is
+    /// not tracked.
+    pub(crate) fn on_complete(&self, id: MessageId, handler: Box<dyn AckHandler>) -> bool {
+        let mut state = self.state();
+        if let Some(slot) = state.pending.get_mut(&id) {
+            *slot = Some(handler);
+            return true;
+        }
+
+        match state.completed(id) {
+            Some(delivery) => {
+                drop(state);
+                handler(id, delivery);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Block until message `id` completes or `timeout` elapses. Returns the
+    /// outcome, if known.
+    pub(crate) fn wait(&self, id: MessageId, timeout: Duration) -> Option<Delivery> {
+        let (state, _) = self
+            .0
+            .completed
+            .wait_timeout_while(self.state(), timeout, |state| {
+                state.pending.contains_key(&id)
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+
+        state.completed(id)
+    }
+
+    /// Block until all messages complete or `timeout` elapses. Returns `false`
+    /// if some messages are still pending.
+    pub(crate) fn wait_all(&self, timeout: Duration) -> bool {
+        let (state, _) = self
+            .0
+            .completed
+            .wait_timeout_while(self.state(), timeout, |state| !state.pending.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+
+        state.pending.is_empty()
+    }
+
+    /// The number of messages that have not been acknowledged.
+    pub(crate) fn len(&self) -> usize {
+        self.state().pending.len()
+    }
+}
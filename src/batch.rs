@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+use crate::{Error, Result, MAX_PAYLOAD_SIZE};
+
+/// A batch of serialized device states, published as a single JSON array on
+/// the Losant `state` topic with `Client::send_batch()`. Each state keeps its
+/// own `time` field, so readings taken between publishes are recorded with
+/// the correct timestamps.
+///
+/// A batch is ready to be sent when any of its count, byte size, or age
+/// thresholds is reached. If the pending states do not fit in a single
+/// message, they are split across as many messages as needed.
+///
+/// See <https://docs.losant.com/mqtt/overview/#publishing-device-state>
+#[derive(Debug, Clone)]
+pub struct Batch {
+    states: Vec<Vec<u8>>,
+    bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+    max_age: Option<Duration>,
+    started: Option<Instant>,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Batch {
+    /// Create an empty `Batch` with no count or age threshold and a size
+    /// threshold of `MAX_PAYLOAD_SIZE`.
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            states: Vec::new(),
+            bytes: 0,
+            max_count: usize::MAX,
+            max_bytes: MAX_PAYLOAD_SIZE,
+            max_age: None,
+            started: None,
+        }
+    }
+
+    /// Sets the number of pending states at which the batch is ready.
+    #[inline]
+    #[must_use]
+    pub const fn max_count(mut self, count: usize) -> Self {
+        self.max_count = count;
+        self
+    }
+
+    /// Sets the serialized size at which the batch is ready, and the maximum
+    /// size of each published message. Values larger than `MAX_PAYLOAD_SIZE`
+    /// are clamped.
+    #[inline]
+    #[must_use]
+    pub const fn max_bytes(mut self, bytes: usize) -> Self {
+        self.max_bytes = if bytes > MAX_PAYLOAD_SIZE {
+            MAX_PAYLOAD_SIZE
+        } else {
+            bytes
+        };
+        self
+    }
+
+    /// Sets the age of the oldest pending state at which the batch is ready.
+    #[inline]
+    #[must_use]
+    pub const fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Serialize and add a state to the batch. Returns `true` if the batch is
+    /// ready to be sent.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error serializing `state`
+    /// - if `state` alone would exceed the maximum message size. States that
+    ///   fit it but not the device's buffer (see `Builder::buffer_size()`)
+    ///   are dropped by `Client::send_batch()` instead
+    pub fn push<S>(&mut self, state: &S) -> Result<bool>
+    where
+        S: serde::Serialize,
+    {
+        let state = serde_json::to_vec(state)?;
+        if state.len() + 2 > self.max_bytes {
+            return Err(Error::PayloadSize);
+        }
+
+        self.bytes += state.len();
+        self.states.push(state);
+        self.started.get_or_insert_with(Instant::now);
+
+        Ok(self.is_ready())
+    }
+
+    /// Whether any of the count, size, or age thresholds has been reached.
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        !self.is_empty()
+            && (self.len() >= self.max_count
+                || self.payload_len() >= self.max_bytes
+                || self
+                    .max_age
+                    .zip(self.started)
+                    .map_or(false, |(max, started)| started.elapsed() >= max))
+    }
+
+    /// The number of pending states.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Whether there are no pending states.
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Discard all pending states.
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.bytes = 0;
+        self.started = None;
+    }
+
+    /// The size of all pending states as a single JSON array.
+    #[inline]
+    fn payload_len(&self) -> usize {
+        // brackets and a comma between each element
+        self.bytes + self.states.len() + 1
+    }
+
+    /// Write the longest prefix of pending states that fits in one message of
+    /// at most `limit` bytes into `buf` as a JSON array. Returns the number of
+    /// states written, which is 0 if the first state doesn't fit on its own.
+    pub(crate) fn fill(&self, buf: &mut Vec<u8>, limit: usize) -> usize {
+        let limit = limit.min(self.max_bytes);
+        buf.clear();
+        if self
+            .states
+            .first()
+            .map_or(true, |state| state.len() + 2 > limit)
+        {
+            return 0;
+        }
+        buf.push(b'[');
+
+        let mut count = 0;
+        for state in &self.states {
+            if count > 0 && buf.len() + 1 + state.len() + 1 > limit {
+                break;
+            }
+            if count > 0 {
+                buf.push(b',');
+            }
+            buf.extend_from_slice(state);
+            count += 1;
+        }
+
+        buf.push(b']');
+        count
+    }
+
+    /// Remove the first `count` pending states after they were published.
+    pub(crate) fn consume(&mut self, count: usize) {
+        self.bytes -= self.states.drain(..count).map(|s| s.len()).sum::<usize>();
+        if self.states.is_empty() {
+            self.started = None;
+        }
+    }
+}
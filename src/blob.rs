@@ -0,0 +1,128 @@
+use std::io;
+
+use crate::{Error, Result};
+
+/// The maximum size of a base64 encoded Losant blob attribute, which leaves
+/// room for the rest of a state within `MAX_PAYLOAD_SIZE`.
+///
+/// See <https://docs.losant.com/devices/attributes/>
+pub const MAX_BLOB_SIZE: usize = 255_000;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Binary data for a Losant blob attribute, serialized as a base64 string,
+/// e.g. `State { data: Snapshot { image: Blob::from_slice(&jpeg)? }, .. }`.
+///
+/// Each blob is limited to `MAX_BLOB_SIZE` when it is created. The state that
+/// carries it is still checked against `MAX_PAYLOAD_SIZE` when published, so
+/// several blobs may have to be sent in separate states.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Blob(String);
+
+impl Blob {
+    /// Create a `Blob` by encoding `data`.
+    ///
+    /// # Errors
+    ///
+    /// - if the encoded data is larger than `MAX_BLOB_SIZE`
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        if data.len().div_ceil(3) * 4 > MAX_BLOB_SIZE {
+            return Err(Error::BlobSize);
+        }
+
+        let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+        encode(data, &mut encoded);
+        Ok(Self(encoded))
+    }
+
+    /// Create a `Blob` by encoding everything read from `reader`. Reading
+    /// stops as soon as the encoded data is too large.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error reading from `reader`
+    /// - if the encoded data is larger than `MAX_BLOB_SIZE`
+    pub fn from_reader(mut reader: impl io::Read) -> Result<Self> {
+        let mut encoded = String::new();
+        // a multiple of 3, so only the last read needs padding
+        let mut buf = [0; 768];
+        let mut filled = 0;
+
+        loop {
+            let read = match reader.read(&mut buf[filled..]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            filled += read;
+
+            let whole = if read == 0 {
+                filled
+            } else {
+                filled - filled % 3
+            };
+            if encoded.len() + whole.div_ceil(3) * 4 > MAX_BLOB_SIZE {
+                return Err(Error::BlobSize);
+            }
+            encode(&buf[..whole], &mut encoded);
+            buf.copy_within(whole..filled, 0);
+            filled -= whole;
+
+            if read == 0 {
+                return Ok(Self(encoded));
+            }
+        }
+    }
+
+    /// The base64 encoded data.
+    #[inline]
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The size of the base64 encoded data in bytes.
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl AsRef<str> for Blob {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl serde::Serialize for Blob {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+/// Append the padded base64 encoding of `data` to `encoded`.
+fn encode(data: &[u8], encoded: &mut String) {
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0, |bits, (i, &byte)| {
+            bits | (usize::from(byte) << (16 - 8 * i))
+        });
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(bits >> (18 - 6 * i)) & 0x3f]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+}
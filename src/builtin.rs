@@ -0,0 +1,170 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_svc::mqtt::client::QoS;
+use log::LevelFilter;
+use serde_json::{json, Value};
+
+use crate::command::Route;
+use crate::shared::{Deferred, WeakClient};
+use crate::{Metrics, State};
+
+/// How long `reboot` waits for its reply to be sent before restarting.
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+/// The largest state payload kept for `resendState`.
+const MAX_LAST_STATE: usize = 4096;
+
+/// A command handled by the crate before the `CommandHandler`, enabled with
+/// `Builder::builtin()`. Each one replies by publishing state, and its name is
+/// reserved once enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// `ping` replies with `{ "pong": <payload> }`, or `{ "pong": true }`
+    /// without a payload.
+    Ping,
+    /// `reboot` replies with `{ "rebooting": true }`, then restarts the chip.
+    Reboot,
+    /// `getDiagnostics` replies with the `Metrics` of the device and the
+    /// seconds since it was built, e.g.
+    /// `{ "published": 120, ..., "deviceAge": 3600 }`. The uptime of the chip
+    /// is reported by `Metric::Uptime` instead.
+    GetDiagnostics,
+    /// `setLogLevel` with a payload of `{ "level": "debug" }` sets
+    /// `log::max_level()`, and replies with `{ "logLevel": "DEBUG" }`, or with
+    /// `{ "commandError": "..." }` if the level is invalid.
+    SetLogLevel,
+    /// `resendState` publishes the last state sent by the device again, or
+    /// replies with `{ "commandError": "no state to resend" }`. States larger
+    /// than 4KB, and states split across several messages, e.g. by
+    /// `Client::send_batch()`, are not kept.
+    ResendState,
+}
+
+impl Builtin {
+    /// The name of the command.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Ping => "ping",
+            Self::Reboot => "reboot",
+            Self::GetDiagnostics => "getDiagnostics",
+            Self::SetLogLevel => "setLogLevel",
+            Self::ResendState => "resendState",
+        }
+    }
+
+    /// The handler for the command.
+    pub(crate) fn route(self, context: Arc<Context>) -> Route {
+        Box::new(move |command: &Value| {
+            let payload = &command["payload"];
+            match self {
+                Self::Ping => {
+                    let pong = if payload.is_null() {
+                        Value::Bool(true)
+                    } else {
+                        payload.clone()
+                    };
+                    context.reply(&json!({ "pong": pong }));
+                }
+                Self::Reboot => {
+                    context.reply(&json!({ "rebooting": true }));
+                    thread::Builder::new()
+                        .name("losant-reboot".to_owned())
+                        .stack_size(2048)
+                        .spawn(|| {
+                            thread::sleep(REBOOT_DELAY);
+                            esp_idf_hal::reset::restart();
+                        })
+                        .ok();
+                }
+                Self::GetDiagnostics => {
+                    let mut diagnostics =
+                        serde_json::to_value((context.metrics)()).unwrap_or_else(|_| json!({}));
+                    diagnostics["deviceAge"] = context.started.elapsed().as_secs().into();
+                    context.reply(&diagnostics);
+                }
+                Self::SetLogLevel => {
+                    match payload["level"].as_str().map(str::parse::<LevelFilter>) {
+                        Some(Ok(level)) => {
+                            log::set_max_level(level);
+                            context.reply(&json!({ "logLevel": level.as_str() }));
+                        }
+                        _ => context.reply(&json!({ "commandError": "invalid log level" })),
+                    }
+                }
+                Self::ResendState => {
+                    if let Some(payload) = context.last_state.get() {
+                        context.publish(payload);
+                    } else {
+                        context.reply(&json!({ "commandError": "no state to resend" }));
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// The last state payload published by a `Device`, kept for `resendState`.
+#[derive(Clone, Default)]
+pub(crate) struct LastState(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl LastState {
+    /// Keep a copy of `payload`, unless it is larger than `MAX_LAST_STATE`.
+    pub(crate) fn set(&self, payload: &[u8]) {
+        let mut last = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if payload.len() > MAX_LAST_STATE {
+            *last = None;
+            return;
+        }
+
+        let last = last.get_or_insert_with(Vec::new);
+        last.clear();
+        last.extend_from_slice(payload);
+    }
+
+    /// Forget the last state, e.g. because only part of it was kept.
+    pub(crate) fn clear(&self) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    fn get(&self) -> Option<Vec<u8>> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// What built-in commands need from the `Device`.
+pub(crate) struct Context {
+    pub(crate) client: WeakClient,
+    pub(crate) state_topic: String,
+    pub(crate) started: Instant,
+    pub(crate) metrics: Box<dyn Fn() -> Metrics + Send + Sync>,
+    pub(crate) last_state: LastState,
+}
+
+impl Context {
+    /// Publish `data` as state.
+    fn reply(&self, data: &Value) {
+        let state = State {
+            data,
+            time: None,
+            flow_version: None,
+            meta: None,
+        };
+        if let Ok(payload) = serde_json::to_vec(&state) {
+            self.publish(payload);
+        }
+    }
+
+    fn publish(&self, payload: Vec<u8>) {
+        self.client.defer(Deferred {
+            topic: self.state_topic.clone(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload,
+        });
+    }
+}
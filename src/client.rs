@@ -1,6 +1,6 @@
 use embedded_svc::mqtt::client::{MessageId, QoS};
 
-use crate::Result;
+use crate::{codec::Codec, Batch, Result};
 
 pub trait Client {
     /// Publish a message to the broker. `QoS::AtMostOnce` (0) or
@@ -34,15 +34,45 @@ pub trait Client {
         payload: impl AsRef<[u8]>,
     ) -> Result<MessageId>;
 
+    /// Publish a typed message to the broker, encoded with `codec`.
+    /// `QoS::AtMostOnce` (0) or `QoS::AtLeastOnce` (1) must be used.
+    ///
+    /// Use the same `Codec` to decode the message on the receiving side.
+    ///
+    /// # Errors
+    ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if the payload is larger than 256KB or the serialization buffer
+    /// - if there was an error encoding `value`
+    /// - if there was an error publishing the payload
+    fn publish_encoded<C, T>(
+        &mut self,
+        topic: impl AsRef<str>,
+        qos: QoS,
+        retain: bool,
+        codec: &C,
+        value: &T,
+    ) -> Result<MessageId>
+    where
+        C: Codec,
+        T: ?Sized + serde::Serialize;
+
     /// Publish device state to the broker. `QoS::AtMostOnce` (0) or
     /// `QoS::AtLeastOnce` (1) must be used.
     ///
-    /// Takes a reference `state` to allow its reuse.
+    /// Takes a reference `state` to allow its reuse. `state` is serialized
+    /// into a buffer owned by the `Device`, which is reused between publishes.
+    ///
+    /// If `state` serializes to an array larger than 256KB, it is split across
+    /// several messages and the ID of the last message is returned. The
+    /// elements are serialized one at a time, so the whole array is never
+    /// held in memory.
     ///
     /// # Errors
     ///
     /// - if `QoS::ExactlyOnce` (2) is used
-    /// - if the payload is larger than 256KB
+    /// - if the payload is larger than 256KB and is not an array
+    /// - if a single element of an array payload is larger than 256KB
     /// - if there was an error serializing `state`
     /// - if there was an error publishing the payload
     ///
@@ -56,10 +86,14 @@ pub trait Client {
     ///
     /// If `state` needs to be reused, consider `publish_state()` instead.
     ///
+    /// If `state` is an array larger than 256KB, it is split across several
+    /// messages and the ID of the last message is returned.
+    ///
     /// # Errors
     ///
     /// - if `QoS::ExactlyOnce` (2) is used
-    /// - if the payload is larger than 256KB
+    /// - if the payload is larger than 256KB and is not an array
+    /// - if a single element of an array payload is larger than 256KB
     /// - if there was an error publishing the payload
     ///
     /// See <https://docs.losant.com/mqtt/overview/#publishing-device-state>
@@ -70,12 +104,76 @@ pub trait Client {
         state: serde_json::Value,
     ) -> Result<MessageId>;
 
-    /// Subscribe to the `topic`. `QoS::AtMostOnce` (0) is used.
+    /// Publish device state to the broker, serialized with `serde-json-core`
+    /// into the caller-supplied `buf` instead of the `Device` buffer. Never
+    /// allocates, which suits fixed-size state structs. `QoS::AtMostOnce` (0)
+    /// or `QoS::AtLeastOnce` (1) must be used.
     ///
     /// # Errors
     ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if `state` does not fit in `buf`
+    /// - if the payload is larger than 256KB
+    /// - if there was an error publishing the payload
+    ///
+    /// See <https://docs.losant.com/mqtt/overview/#publishing-device-state>
+    #[cfg(feature = "json-core")]
+    fn send_state_core<S>(
+        &mut self,
+        qos: QoS,
+        retain: bool,
+        state: &S,
+        buf: &mut [u8],
+    ) -> Result<MessageId>
+    where
+        S: serde::Serialize;
+
+    /// Publish `states` to the broker as JSON arrays, split across as many
+    /// messages as needed. `QoS::AtMostOnce` (0) or `QoS::AtLeastOnce` (1)
+    /// must be used. States are serialized one at a time into the
+    /// serialization buffer, and each message is published once it is full.
+    ///
+    /// # Errors
+    ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if a single state is larger than the serialization buffer
+    /// - if there was an error serializing a state
+    /// - if there was an error publishing a payload
+    ///
+    /// See <https://docs.losant.com/mqtt/overview/#publishing-device-state>
+    fn send_states<S>(&mut self, qos: QoS, retain: bool, states: &[S]) -> Result<Vec<MessageId>>
+    where
+        S: serde::Serialize;
+
+    /// Publish all pending states in `batch` to the broker as JSON arrays,
+    /// split across as many messages as needed. `QoS::AtMostOnce` (0) or
+    /// `QoS::AtLeastOnce` (1) must be used.
+    ///
+    /// States are removed from `batch` as they are published, so on error the
+    /// remaining states can be sent again later. A state larger than the
+    /// serialization buffer, which `Batch::push()` can't check, is removed
+    /// without being published.
+    ///
+    /// # Errors
+    ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if a single state is larger than the serialization buffer
+    /// - if there was an error publishing a payload
+    ///
+    /// See <https://docs.losant.com/mqtt/overview/#publishing-device-state>
+    fn send_batch(&mut self, qos: QoS, retain: bool, batch: &mut Batch) -> Result<Vec<MessageId>>;
+
+    /// Subscribe to the `topic` filter. `QoS::AtMostOnce` (0) or
+    /// `QoS::AtLeastOnce` (1) must be used.
+    ///
+    /// # Errors
+    ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if `topic` is not a valid MQTT topic filter, or is reserved by Losant
     /// - if there was an error subscribing to the topic
-    fn subscribe(&mut self, topic: impl AsRef<str>) -> Result<MessageId>;
+    ///
+    /// See <https://docs.losant.com/mqtt/overview/#custom-mqtt-topics>
+    fn subscribe(&mut self, topic: impl AsRef<str>, qos: QoS) -> Result<MessageId>;
 
     /// Unsubscribe from the `topic`.
     ///
@@ -0,0 +1,104 @@
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::Result;
+
+/// An encoding for typed messages on custom topics. Losant `state` messages
+/// are always JSON, but messages on other topics can use a more compact
+/// encoding.
+///
+/// See `Client::publish_encoded()`.
+pub trait Codec {
+    /// Encode `value` into `writer`.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error serializing `value` or writing to `writer`
+    fn encode<T, W>(&self, value: &T, writer: W) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+        W: io::Write;
+
+    /// Decode a value from the payload of a received message.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error deserializing `bytes`
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned;
+}
+
+/// JSON encoding with `serde_json`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T, W>(&self, value: &T, writer: W) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+        W: io::Write,
+    {
+        serde_json::to_writer(writer, value).map_err(Into::into)
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_slice(bytes).map_err(Into::into)
+    }
+}
+
+/// CBOR encoding with `ciborium`.
+///
+/// See <https://cbor.io>
+#[cfg(feature = "cbor")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T, W>(&self, value: &T, writer: W) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+        W: io::Write,
+    {
+        ciborium::ser::into_writer(value, writer).map_err(Into::into)
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        ciborium::de::from_reader(bytes).map_err(Into::into)
+    }
+}
+
+/// MessagePack encoding with `rmp-serde`. Structs are encoded as maps with
+/// field names, so they can be decoded without knowing the field order.
+///
+/// See <https://msgpack.org>
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T, W>(&self, value: &T, mut writer: W) -> Result<()>
+    where
+        T: ?Sized + Serialize,
+        W: io::Write,
+    {
+        rmp_serde::encode::write_named(&mut writer, value).map_err(Into::into)
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        rmp_serde::from_slice(bytes).map_err(Into::into)
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::{Deserializer, Error as _};
+use serde_json::Value;
+
+/// A Losant command message, e.g.
+/// `{ "name": "setLed", "payload": { "ledR": 0, ... }, "time": { "$date": "2016-06-13T04:00:00.000Z" } }`.
+///
+/// See <https://docs.losant.com/mqtt/overview/#receiving-commands>
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Command<P = Value> {
+    /// The name of the command.
+    pub name: String,
+    /// The payload of the command.
+    pub payload: P,
+    /// When the command was sent.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub time: SystemTime,
+}
+
+/// The forms of command time: `{ "$date": "..." }` as sent by Losant, a bare
+/// timestamp, or milliseconds since the Unix epoch.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Time {
+    Date {
+        #[serde(rename = "$date")]
+        date: String,
+    },
+    Timestamp(String),
+    Millis(u64),
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> std::result::Result<SystemTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match <Time as serde::Deserialize>::deserialize(deserializer)? {
+        Time::Date { date: time } | Time::Timestamp(time) => {
+            parse_time(&time).ok_or_else(|| D::Error::custom(format!("invalid time `{time}`")))
+        }
+        Time::Millis(millis) => Ok(UNIX_EPOCH + Duration::from_millis(millis)),
+    }
+}
+
+/// The time a Losant command was sent, if it has a valid one.
+pub(crate) fn time(command: &Value) -> Option<SystemTime> {
+    deserialize_time(&command["time"]).ok()
+}
+
+/// Parse a UTC timestamp, e.g. `2016-06-13T04:00:00.000Z`.
+fn parse_time(time: &str) -> Option<SystemTime> {
+    let (date, time) = time.strip_suffix('Z')?.split_once('T')?;
+    let (year, date) = date.split_once('-')?;
+    let (month, day) = date.split_once('-')?;
+    let (hour, time) = time.split_once(':')?;
+    let (minute, second) = time.split_once(':')?;
+    let (second, fraction) = second.split_once('.').unwrap_or((second, ""));
+
+    let (year, month, day): (i64, i64, i64) =
+        (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+    let (hour, minute, second): (u64, u64, u64) = (
+        hour.parse().ok()?,
+        minute.parse().ok()?,
+        second.parse().ok()?,
+    );
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let nanos = if fraction.is_empty() {
+        0
+    } else if fraction.bytes().all(|b| b.is_ascii_digit()) {
+        let digits = &fraction[..fraction.len().min(9)];
+        let scale = 10_u32.pow(9 - u32::try_from(digits.len()).ok()?);
+        digits.parse::<u32>().ok()? * scale
+    } else {
+        return None;
+    };
+
+    // days since the Unix epoch, see
+    // <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146_097 + day_of_era - 719_468).ok()?;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+}
+
+/// Handles a Losant command as JSON.
+pub(crate) type Route = Box<dyn FnMut(&Value) + Send>;
+
+/// Losant command handlers by command name, with a fallback for commands
+/// without a handler of their own.
+#[derive(Default)]
+pub(crate) struct Router {
+    routes: HashMap<String, Route>,
+    fallback: Option<Route>,
+}
+
+impl Router {
+    /// Set the handler for commands named `name`, replacing any previous
+    /// handler.
+    pub(crate) fn insert(&mut self, name: String, route: Route) {
+        self.routes.insert(name, route);
+    }
+
+    /// Set the handler for commands that have no handler by name.
+    pub(crate) fn fallback(&mut self, route: Route) {
+        self.fallback = Some(route);
+    }
+
+    /// Pass `command` to the handler for its name, or the fallback.
+    pub(crate) fn dispatch(&mut self, command: &Value) {
+        let route = command["name"]
+            .as_str()
+            .and_then(|name| self.routes.get_mut(name));
+        if let Some(route) = route {
+            route(command);
+        } else if let Some(fallback) = &mut self.fallback {
+            fallback(command);
+        }
+    }
+}
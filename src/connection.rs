@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Shared {
+    connected: Mutex<bool>,
+    changed: Condvar,
+    connects: AtomicU32,
+}
+
+/// The connection status of the MQTT client, shared between a `Device` and
+/// its MQTT event handler.
+#[derive(Clone, Default)]
+pub(crate) struct Connection(Arc<Shared>);
+
+impl Connection {
+    fn connected(&self) -> MutexGuard<'_, bool> {
+        self.0
+            .connected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Record a connect or disconnect and wake any waiters.
+    pub(crate) fn set(&self, connected: bool) {
+        if connected {
+            self.0.connects.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.connected() = connected;
+        self.0.changed.notify_all();
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        *self.connected()
+    }
+
+    /// The number of times the client reconnected after its first connect.
+    pub(crate) fn reconnects(&self) -> u32 {
+        self.0.connects.load(Ordering::Relaxed).saturating_sub(1)
+    }
+
+    /// Block until connected or `timeout` elapses. Returns `false` if not
+    /// connected in time.
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let (connected, _) = self
+            .0
+            .changed
+            .wait_timeout_while(self.connected(), timeout, |connected| !*connected)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        *connected
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::Value;
+
+use crate::{Result, Storage};
+
+const STORAGE_KEY: &str = "losant_recent";
+
+/// A bounded set of recently seen message keys, used to drop messages that
+/// the broker delivers more than once. If `storage` is set, the keys are
+/// persisted to it whenever one is added.
+pub(crate) struct Recent {
+    keys: VecDeque<u64>,
+    capacity: usize,
+    storage: Option<Box<dyn Storage + Send>>,
+}
+
+impl Recent {
+    /// Create a `Recent`, loading the keys persisted to `storage`, if any.
+    pub(crate) fn new(
+        capacity: usize,
+        mut storage: Option<Box<dyn Storage + Send>>,
+    ) -> Result<Self> {
+        let mut keys: VecDeque<u64> = match storage.as_mut().map(|s| s.load(STORAGE_KEY)) {
+            Some(Ok(Some(keys))) => serde_json::from_slice(&keys).unwrap_or_default(),
+            Some(Err(e)) => return Err(e),
+            _ => VecDeque::new(),
+        };
+        // keep the newest keys
+        keys.drain(..keys.len().saturating_sub(capacity));
+
+        Ok(Self {
+            keys,
+            capacity,
+            storage,
+        })
+    }
+
+    /// Record `key`, evicting the oldest key if full. Returns `false` if `key`
+    /// was already seen.
+    pub(crate) fn insert(&mut self, key: u64) -> bool {
+        if self.keys.contains(&key) {
+            return false;
+        }
+
+        if self.keys.len() == self.capacity {
+            self.keys.pop_front();
+        }
+        self.keys.push_back(key);
+        if let Some(storage) = &mut self.storage {
+            if let Ok(keys) = serde_json::to_vec(&self.keys) {
+                storage.store(STORAGE_KEY, &keys).ok();
+            }
+        }
+
+        true
+    }
+}
+
+/// Hash a message into a key for `Recent`.
+pub(crate) fn key(message: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Drops Losant commands that were already received within a window, keyed on
+/// their time, name and payload, or that were sent too long ago.
+pub(crate) struct Replay {
+    seen: VecDeque<(Instant, u64)>,
+    window: Option<Duration>,
+    max_age: Option<Duration>,
+}
+
+impl Replay {
+    pub(crate) const fn new(window: Option<Duration>, max_age: Option<Duration>) -> Self {
+        Self {
+            seen: VecDeque::new(),
+            window,
+            max_age,
+        }
+    }
+
+    /// Record `command`. Returns `false` if it is a duplicate or too old.
+    pub(crate) fn accept(&mut self, command: &Value) -> bool {
+        let time = crate::command::time(command);
+        if let (Some(max_age), Some(time)) = (self.max_age, time) {
+            // commands from the future, e.g. before SNTP sync, are let through
+            if SystemTime::now()
+                .duration_since(time)
+                .map_or(false, |age| age > max_age)
+            {
+                return false;
+            }
+        }
+
+        let Some(window) = self.window else {
+            return true;
+        };
+        let now = Instant::now();
+        while let Some(&(seen, _)) = self.seen.front() {
+            if now.duration_since(seen) <= window {
+                break;
+            }
+            self.seen.pop_front();
+        }
+
+        let key = key((
+            time,
+            command["name"].as_str(),
+            command["payload"].to_string(),
+        ));
+        if self.seen.iter().any(|&(_, seen)| seen == key) {
+            return false;
+        }
+
+        self.seen.push_back((now, key));
+        true
+    }
+}
@@ -1,17 +1,36 @@
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use embedded_svc::mqtt::client::{Event, MessageId, QoS};
+use embedded_svc::mqtt::client::{Details, Event, MessageId, QoS};
 use esp_idf_svc::mqtt::client::{
-    EspMqttClient, EspMqttMessage, MqttClientConfiguration, MqttProtocolVersion,
+    EspMqttClient, EspMqttMessage, LwtConfiguration, MqttClientConfiguration, MqttProtocolVersion,
 };
 use esp_idf_svc::tls::X509;
 use esp_idf_sys::EspError;
+use serde_json::Value;
 
-use crate::{client::Client, Error, Result};
+use crate::ack::{Delivery, Inflight};
+use crate::builtin::{self, LastState};
+use crate::codec::{Codec, Json};
+use crate::command::Router;
+use crate::connection::Connection;
+use crate::dedup::{Recent, Replay};
+use crate::health;
+use crate::logs::LogForwarder;
+use crate::metrics::Counters;
+use crate::remote_config::RemoteConfig;
+use crate::schedule::{Dispatch, Worker};
+use crate::shared::{Deferred, SharedClient};
+use crate::topic::{Fragment, Fragments, RouteHandler, Routes};
+use crate::transfer::Uploads;
+use crate::{
+    client::Client, Batch, Builtin, Error, Health, Metrics, Operation, Result, Scheduler, Storage,
+    Transfer, MAX_PAYLOAD_SIZE,
+};
 
 const BROKER_HOST: &str = "broker.losant.com";
-/// See <https://docs.losant.com/mqtt/overview/#message-limits>
-const MAX_PAYLOAD_SIZE: usize = 256_000;
+/// The number of recent commands remembered to drop redeliveries.
+const RECENT_COMMANDS: usize = 32;
 /// DigiCert Global Root CA certificate.
 #[allow(clippy::doc_markdown)]
 const ROOT_CA_CERT: X509<'_> =
@@ -21,12 +40,28 @@ pub type EventResult<'a> = std::result::Result<Event<EspMqttMessage<'a>>, EspErr
 pub trait EventResultHandler = for<'b> FnMut(&'b EventResult<'b>) + Send + 'static;
 pub trait ConfigUpdater = FnOnce(&mut MqttClientConfiguration<'_>) + 'static;
 pub trait CommandHandler<Command> = for<'b> FnMut(&'b Command) + Send + 'static;
+pub trait TopicHandler<T> = for<'b> FnMut(&'b str, &'b T) + Send + 'static;
+pub trait AckHandler = FnOnce(MessageId, Delivery) + Send + 'static;
 
 // TODO: docs
 pub struct Device<'a> {
     state_topic: String,
+    command_topic: String,
     pub config: MqttClientConfiguration<'a>,
-    client: EspMqttClient,
+    client: SharedClient,
+    will: Option<Vec<u8>>,
+    buf: Vec<u8>,
+    buf_limit: usize,
+    routes: Routes,
+    inflight: Inflight,
+    connection: Connection,
+    metrics: Counters,
+    last_state: Option<LastState>,
+    subscriptions: Vec<String>,
+    unsubscribe_on_shutdown: bool,
+    _scheduler: Option<Worker>,
+    uploads: Option<Uploads>,
+    _health: Option<health::Worker>,
 }
 
 impl<'a> Device<'a> {
@@ -40,7 +75,228 @@ impl<'a> Device<'a> {
             handler: None,
             command_handler: None,
             config: None,
+            buffer: None,
+            buffer_size: None,
+            last_will: None,
+            online_state: None,
+            unsubscribe_on_shutdown: false,
+            persistent_session: false,
+            session_storage: None,
+            scheduler: None,
+            router: Router::default(),
+            #[cfg(feature = "ota")]
+            ota: None,
+            dedup_window: None,
+            max_command_age: None,
+            transfer: None,
+            remote_config: None,
+            health: None,
+            logs: None,
+            builtins: Vec::new(),
+        }
+    }
+
+    /// Subscribe to the topic `filter` and pass messages on matching topics to
+    /// `handler`, deserialized from JSON into `T`. `filter` may contain the
+    /// MQTT `+` and `#` wildcards. Messages that fail to deserialize are
+    /// ignored.
+    ///
+    /// Matched messages are not passed to the handler set with
+    /// `Builder::handler()`.
+    ///
+    /// # Errors
+    ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if `filter` is not a valid MQTT topic filter, or is reserved by Losant
+    /// - if there was an error subscribing to the topic
+    pub fn subscribe_typed<T>(
+        &mut self,
+        filter: impl Into<String>,
+        qos: QoS,
+        handler: impl TopicHandler<T>,
+    ) -> Result<MessageId>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        self.subscribe_with(filter, qos, Json, handler)
+    }
+
+    /// Like `subscribe_typed()`, but messages are decoded with `codec`.
+    ///
+    /// # Errors
+    ///
+    /// - if `QoS::ExactlyOnce` (2) is used
+    /// - if `filter` is not a valid MQTT topic filter, or is reserved by Losant
+    /// - if there was an error subscribing to the topic
+    pub fn subscribe_with<C, T>(
+        &mut self,
+        filter: impl Into<String>,
+        qos: QoS,
+        codec: C,
+        mut handler: impl TopicHandler<T>,
+    ) -> Result<MessageId>
+    where
+        C: Codec + Send + 'static,
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        let filter = filter.into();
+        self.routes.insert(
+            filter.clone(),
+            Box::new(move |topic, data| {
+                if let Ok(value) = codec.decode::<T>(data) {
+                    handler(topic, &value);
+                }
+            }),
+        );
+
+        self.subscribe(&filter, qos).map_err(|e| {
+            self.routes.remove(&filter);
+            e
+        })
+    }
+
+    /// Upload `data` as a file named `name` in chunks, blocking until the
+    /// receiver acknowledges every chunk. See `Transfer`.
+    ///
+    /// # Errors
+    ///
+    /// - if transfers were not enabled with `Builder::transfer()`
+    /// - if there was an error publishing the manifest or a chunk
+    /// - if the receiver rejected the upload, or stopped acknowledging chunks
+    pub fn upload(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let uploads = self
+            .uploads
+            .clone()
+            .ok_or(Error::Transfer("transfers are not enabled"))?;
+        uploads.upload(self, name, data)
+    }
+
+    /// Like `subscribe_with()`, but `handler` is passed the raw payload.
+    fn subscribe_raw(
+        &mut self,
+        filter: String,
+        qos: QoS,
+        handler: RouteHandler,
+    ) -> Result<MessageId> {
+        self.routes.insert(filter.clone(), handler);
+        self.subscribe(&filter, qos).map_err(|e| {
+            self.routes.remove(&filter);
+            e
+        })
+    }
+
+    /// Whether the client is currently connected to the broker.
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    /// Block until the client is connected to the broker, or until `timeout`
+    /// elapses. Returns `false` if not connected in time.
+    pub fn wait_connected(&self, timeout: Duration) -> bool {
+        self.connection.wait(timeout)
+    }
+
+    /// Block until the broker acknowledges the QoS 1 message `id`, or until
+    /// `timeout` elapses. Returns `false` if the message was not acknowledged
+    /// in time, was deleted from the outbox, or is not tracked. The outcomes
+    /// of the last 64 messages are remembered for 5 minutes after they
+    /// complete.
+    pub fn wait_for_ack(&self, id: MessageId, timeout: Duration) -> bool {
+        self.inflight.wait(id, timeout) == Some(Delivery::Acknowledged)
+    }
+
+    /// Sets a handler that is called once the QoS 1 message `id` is
+    /// acknowledged or deleted from the outbox. If that already happened,
+    /// `handler` is called immediately, if it is still remembered (see
+    /// `wait_for_ack()`).
+    /// Returns `false` if `id` is not tracked, in which case `handler` is never
+    /// called.
+    ///
+    /// The handler is called from the MQTT event handler, so it should not
+    /// block.
+    pub fn on_ack(&self, id: MessageId, handler: impl AckHandler) -> bool {
+        self.inflight.on_complete(id, Box::new(handler))
+    }
+
+    /// The number of QoS 1 messages that have been published but not yet
+    /// acknowledged by the broker.
+    #[must_use]
+    pub fn unacked(&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// A snapshot of the client metrics, e.g. to publish or log them.
+    #[must_use]
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.snapshot(&self.connection, &self.inflight)
+    }
+
+    /// Publish the last will state set with `Builder::last_will()`, if any,
+    /// then disconnect cleanly. See `shutdown_with_state()`.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error publishing the last will state
+    /// - if there was an error unsubscribing from a topic
+    pub fn shutdown(mut self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        self.client.close();
+
+        // an intentional disconnect doesn't trigger the will, so send it here
+        if let Some(will) = self.will.take() {
+            let topic = self.state_topic.clone();
+            self.publish(topic, QoS::AtLeastOnce, false, &will)?;
+        }
+
+        self.disconnect(deadline)
+    }
+
+    /// Publish a final `state`, then disconnect cleanly, e.g. before entering
+    /// deep sleep.
+    ///
+    /// Messages published from the MQTT event handler are no longer accepted,
+    /// but those already waiting for the client are still sent. Blocks until
+    /// all QoS 1 messages are acknowledged or `timeout` elapses, unsubscribes
+    /// from all topics if `Builder::unsubscribe_on_shutdown()` is set, and
+    /// disconnects from the broker. Returns `false` if some messages were not
+    /// acknowledged in time.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error serializing or publishing `state`
+    /// - if there was an error unsubscribing from a topic
+    pub fn shutdown_with_state<S>(mut self, timeout: Duration, state: &S) -> Result<bool>
+    where
+        S: serde::Serialize,
+    {
+        let deadline = Instant::now() + timeout;
+        self.client.close();
+        self.send_state(QoS::AtLeastOnce, false, state)?;
+        self.disconnect(deadline)
+    }
+
+    /// Enqueue deferred messages, wait for in-flight messages until
+    /// `deadline`, unsubscribe if configured, and drop the client to
+    /// disconnect.
+    fn disconnect(mut self, deadline: Instant) -> Result<bool> {
+        self.client.flush();
+        let acked = self
+            .inflight
+            .wait_all(deadline.saturating_duration_since(Instant::now()));
+
+        if self.unsubscribe_on_shutdown {
+            for topic in std::mem::take(&mut self.subscriptions) {
+                self.unsubscribe(topic)?;
+            }
         }
+
+        Ok(acked)
+    }
+
+    /// The largest payload the device serializes, see `Builder::buffer_size()`.
+    pub(crate) const fn buf_limit(&self) -> usize {
+        self.buf_limit
     }
 
     /// Check QoS and payload size for use in message publishing functions.
@@ -57,9 +313,124 @@ impl<'a> Device<'a> {
 
         Ok(())
     }
+
+    /// Publish or enqueue `payload`, tracking it until it is acknowledged if
+    /// it is QoS 1, and record it in the metrics.
+    fn send(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+        enqueue: bool,
+    ) -> Result<MessageId> {
+        let sent = Self::check_publish(qos, payload).and_then(|()| {
+            let started = Instant::now();
+            let mut client = self.client.lock();
+            let id = if enqueue {
+                client
+                    .enqueue(topic, qos, retain, payload)
+                    .map_err(Error::client(Operation::Enqueue, topic))?
+            } else {
+                client
+                    .publish(topic, qos, retain, payload)
+                    .map_err(Error::client(Operation::Publish, topic))?
+            };
+            drop(client);
+
+            self.metrics.published(payload.len(), started.elapsed());
+            if let (Some(last_state), true) = (&self.last_state, topic == self.state_topic) {
+                last_state.set(payload);
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(id, size = payload.len(), enqueue, "published");
+            Ok(self.inflight.track(qos, id))
+        });
+
+        sent.map_err(|e| self.metrics.failed(e))
+    }
+
+    /// Forget the last state kept for `resendState`, once a state is split
+    /// across several messages and only its last part was kept.
+    fn forget_state(&self) {
+        if let Some(last_state) = &self.last_state {
+            last_state.clear();
+        }
+    }
+
+    /// Publish the elements of `states` as JSON arrays, split across as many
+    /// messages as needed. Elements are serialized one at a time, so the whole
+    /// array is never held in memory. Returns `None` if `states` does not
+    /// serialize as an array.
+    fn send_elements<T>(
+        &mut self,
+        qos: QoS,
+        retain: bool,
+        states: &T,
+    ) -> Result<Option<Vec<MessageId>>>
+    where
+        T: ?Sized + serde::Serialize,
+    {
+        let limit = self.buf_limit;
+        let mut element = Vec::new();
+        let mut ids = Vec::new();
+        // publishing records its own failures
+        let mut published = true;
+        self.buf.clear();
+        let sequence = crate::serde::for_each_element(
+            states,
+            &mut element,
+            limit.saturating_sub(2),
+            |element| {
+                // brackets and a comma between each element
+                if !self.buf.is_empty() && self.buf.len() + element.len() + 2 > limit {
+                    self.buf.push(b']');
+                    let id = self.send(&self.state_topic, qos, retain, &self.buf, false);
+                    published = id.is_ok();
+                    ids.push(id?);
+                    self.forget_state();
+                    self.buf.clear();
+                }
+                self.buf.push(if self.buf.is_empty() { b'[' } else { b',' });
+                self.buf.extend_from_slice(element);
+                Ok(())
+            },
+        );
+
+        match sequence {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(e) if !published => return Err(e),
+            Err(Error::PayloadSize | Error::BufferSize) => {
+                return Err(self.metrics.failed(crate::serde::size_error(limit)));
+            }
+            Err(e) => return Err(self.metrics.failed(e)),
+        }
+        if !self.buf.is_empty() {
+            self.buf.push(b']');
+            ids.push(self.send(&self.state_topic, qos, retain, &self.buf, false)?);
+            if ids.len() > 1 {
+                self.forget_state();
+            }
+        }
+
+        Ok(Some(ids))
+    }
+}
+
+impl Drop for Device<'_> {
+    fn drop(&mut self) {
+        // destroy the client here, not on a thread that still holds the
+        // shared client, e.g. the MQTT event handler
+        drop(self.client.take());
+    }
 }
 
 impl<'a> Client for Device<'a> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = topic.as_ref(), qos = ?qos, retain), err)
+    )]
     fn publish(
         &mut self,
         topic: impl AsRef<str>,
@@ -67,13 +438,13 @@ impl<'a> Client for Device<'a> {
         retain: bool,
         payload: impl AsRef<[u8]>,
     ) -> Result<MessageId> {
-        let payload = payload.as_ref();
-        Self::check_publish(qos, payload)?;
-        self.client
-            .publish(topic.as_ref(), qos, retain, payload)
-            .map_err(Error::from)
+        self.send(topic.as_ref(), qos, retain, payload.as_ref(), false)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = topic.as_ref(), qos = ?qos, retain), err)
+    )]
     fn enqueue(
         &mut self,
         topic: impl AsRef<str>,
@@ -81,47 +452,165 @@ impl<'a> Client for Device<'a> {
         retain: bool,
         payload: impl AsRef<[u8]>,
     ) -> Result<MessageId> {
-        let payload = payload.as_ref();
-        Self::check_publish(qos, payload)?;
-        self.client
-            .enqueue(topic.as_ref(), qos, retain, payload)
-            .map_err(Error::from)
+        self.send(topic.as_ref(), qos, retain, payload.as_ref(), true)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = topic.as_ref(), qos = ?qos, retain), err)
+    )]
+    fn publish_encoded<C, T>(
+        &mut self,
+        topic: impl AsRef<str>,
+        qos: QoS,
+        retain: bool,
+        codec: &C,
+        value: &T,
+    ) -> Result<MessageId>
+    where
+        C: Codec,
+        T: ?Sized + serde::Serialize,
+    {
+        crate::serde::encode_buf(codec, &mut self.buf, self.buf_limit, value)
+            .map_err(|e| self.metrics.failed(e))?;
+        self.send(topic.as_ref(), qos, retain, &self.buf, false)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = self.state_topic.as_str(), qos = ?qos), err)
+    )]
     fn send_state<S>(&mut self, qos: QoS, retain: bool, state: &S) -> Result<MessageId>
     where
         S: serde::Serialize,
     {
-        let payload = serde_json::to_string(&state).map_err(Error::from)?;
-        let payload = payload.as_bytes();
-        Self::check_publish(qos, payload)?;
-        self.client
-            .publish(&self.state_topic, qos, retain, payload)
-            .map_err(Error::from)
+        if let Err(e) = crate::serde::to_json_buf(&mut self.buf, self.buf_limit, state) {
+            if matches!(e, Error::PayloadSize | Error::BufferSize) {
+                if let Some(mut ids) = self.send_elements(qos, retain, state)? {
+                    return ids.pop().ok_or(e);
+                }
+            }
+
+            return Err(self.metrics.failed(e));
+        }
+
+        self.send(&self.state_topic, qos, retain, &self.buf, false)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = self.state_topic.as_str(), qos = ?qos), err)
+    )]
     fn send_state_json(
         &mut self,
         qos: QoS,
         retain: bool,
         state: serde_json::Value,
     ) -> Result<MessageId> {
-        let payload = state.to_string();
-        let payload = payload.as_bytes();
-        Self::check_publish(qos, payload)?;
-        self.client
-            .publish(&self.state_topic, qos, retain, payload)
-            .map_err(Error::from)
+        if let Err(e) = crate::serde::to_json_buf(&mut self.buf, self.buf_limit, &state) {
+            if matches!(e, Error::PayloadSize | Error::BufferSize) && state.is_array() {
+                if let Some(mut ids) = self.send_elements(qos, retain, &state)? {
+                    return ids.pop().ok_or(e);
+                }
+            }
+
+            return Err(self.metrics.failed(e));
+        }
+
+        self.send(&self.state_topic, qos, retain, &self.buf, false)
     }
 
-    fn subscribe(&mut self, topic: impl AsRef<str>) -> Result<MessageId> {
-        self.client
-            .subscribe(topic.as_ref(), QoS::AtMostOnce)
-            .map_err(Error::from)
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = self.state_topic.as_str(), qos = ?qos, count = states.len()), err)
+    )]
+    fn send_states<S>(&mut self, qos: QoS, retain: bool, states: &[S]) -> Result<Vec<MessageId>>
+    where
+        S: serde::Serialize,
+    {
+        Ok(self.send_elements(qos, retain, states)?.unwrap_or_default())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = self.state_topic.as_str(), qos = ?qos), err)
+    )]
+    fn send_batch(&mut self, qos: QoS, retain: bool, batch: &mut Batch) -> Result<Vec<MessageId>> {
+        let mut ids = Vec::new();
+
+        while !batch.is_empty() {
+            let count = batch.fill(&mut self.buf, self.buf_limit);
+            if count == 0 {
+                // it never fits, so it would block every later send
+                batch.consume(1);
+                let e = crate::serde::size_error(self.buf_limit);
+                return Err(self.metrics.failed(e));
+            }
+            ids.push(self.send(&self.state_topic, qos, retain, &self.buf, false)?);
+            batch.consume(count);
+            if ids.len() > 1 || !batch.is_empty() {
+                self.forget_state();
+            }
+        }
+
+        Ok(ids)
+    }
+
+    #[cfg(feature = "json-core")]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = self.state_topic.as_str(), qos = ?qos), err)
+    )]
+    fn send_state_core<S>(
+        &mut self,
+        qos: QoS,
+        retain: bool,
+        state: &S,
+        buf: &mut [u8],
+    ) -> Result<MessageId>
+    where
+        S: serde::Serialize,
+    {
+        let len = serde_json_core::to_slice(state, buf)
+            .map_err(|_| self.metrics.failed(Error::BufferSize))?;
+        self.send(&self.state_topic, qos, retain, &buf[..len], false)
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = topic.as_ref(), qos = ?qos), err)
+    )]
+    fn subscribe(&mut self, topic: impl AsRef<str>, qos: QoS) -> Result<MessageId> {
+        let topic = topic.as_ref();
+        if qos == QoS::ExactlyOnce {
+            return Err(Error::QoS2NotSupported);
+        }
+
+        let own = self.command_topic.trim_end_matches("command");
+        crate::topic::validate_filter(topic, own)?;
+        let id = self
+            .client
+            .lock()
+            .subscribe(topic, qos)
+            .map_err(Error::client(Operation::Subscribe, topic))?;
+        if !self.subscriptions.iter().any(|t| t == topic) {
+            self.subscriptions.push(topic.to_owned());
+        }
+
+        Ok(id)
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(topic = topic.as_ref()), err)
+    )]
     fn unsubscribe(&mut self, topic: impl AsRef<str>) -> Result<MessageId> {
-        self.client.unsubscribe(topic.as_ref()).map_err(Error::from)
+        self.routes.remove(topic.as_ref());
+        self.subscriptions.retain(|t| t != topic.as_ref());
+        self.client
+            .lock()
+            .unsubscribe(topic.as_ref())
+            .map_err(Error::client(Operation::Unsubscribe, topic.as_ref()))
     }
 }
 
@@ -133,6 +622,24 @@ pub struct Builder<'a, Command> {
     handler: Option<Box<dyn EventResultHandler>>,
     command_handler: Option<Box<dyn CommandHandler<Command>>>,
     config: Option<Box<dyn ConfigUpdater>>,
+    buffer: Option<Vec<u8>>,
+    buffer_size: Option<usize>,
+    last_will: Option<serde_json::Result<Vec<u8>>>,
+    online_state: Option<serde_json::Result<Vec<u8>>>,
+    unsubscribe_on_shutdown: bool,
+    persistent_session: bool,
+    session_storage: Option<Box<dyn Storage + Send>>,
+    scheduler: Option<Scheduler>,
+    router: Router,
+    #[cfg(feature = "ota")]
+    ota: Option<crate::ota::Start>,
+    dedup_window: Option<Duration>,
+    max_command_age: Option<Duration>,
+    transfer: Option<Transfer>,
+    remote_config: Option<crate::remote_config::Start>,
+    health: Option<Health>,
+    logs: Option<LogForwarder>,
+    builtins: Vec<Builtin>,
 }
 
 impl<'a, Command> Builder<'a, Command>
@@ -158,20 +665,71 @@ where
     }
 
     /// Sets the handler for all MQTT events except Losant commands, which are
-    /// intercepted by `command_handler()`.
+    /// intercepted by `command_handler()`, and messages on topics subscribed
+    /// to with a handler. Those are reassembled if ESP-MQTT receives them in
+    /// fragments, because they are larger than its `buffer_size`.
     #[must_use]
     pub fn handler(mut self, handler: impl EventResultHandler) -> Self {
         self.handler = Some(Box::new(handler));
         self
     }
 
-    /// Sets the handler for all Losant command messages.
+    /// Sets the handler for Losant command messages that have no handler set
+    /// with `on_command()`.
     #[must_use]
     pub fn command_handler(mut self, handler: impl CommandHandler<Command>) -> Self {
         self.command_handler = Some(Box::new(handler));
         self
     }
 
+    /// Sets the handler for Losant commands named `name`, which is passed the
+    /// command payload, e.g.
+    /// `.on_command("setLed", |color: &LedColor| ...)`. Commands with a
+    /// payload that cannot be deserialized as `P` are dropped.
+    #[must_use]
+    pub fn on_command<P>(
+        mut self,
+        name: impl Into<String>,
+        mut handler: impl CommandHandler<P>,
+    ) -> Self
+    where
+        P: for<'de> serde::Deserialize<'de> + 'static,
+    {
+        self.router.insert(
+            name.into(),
+            Box::new(move |command: &Value| {
+                if let Ok(payload) = P::deserialize(&command["payload"]) {
+                    handler(&payload);
+                }
+            }),
+        );
+        self
+    }
+
+    /// Like `on_command()`, but the handler is passed the whole command,
+    /// including when it was sent, e.g.
+    /// `.on_command_envelope("setLed", |command: &Command<LedColor>| ...)`.
+    #[must_use]
+    pub fn on_command_envelope<P>(
+        mut self,
+        name: impl Into<String>,
+        mut handler: impl CommandHandler<crate::Command<P>>,
+    ) -> Self
+    where
+        P: for<'de> serde::Deserialize<'de> + 'static,
+    {
+        self.router.insert(
+            name.into(),
+            Box::new(move |command: &Value| {
+                if let Ok(command) = <crate::Command<P> as serde::Deserialize>::deserialize(command)
+                {
+                    handler(&command);
+                }
+            }),
+        );
+        self
+    }
+
     /// Updates the `MqttClientConfiguration` using the provided closure, after
     /// the config is built. If `client_id` is set, it will have lower
     /// priority than `id()` or `losant_device_id` in cfg.toml.
@@ -181,14 +739,197 @@ where
         self
     }
 
+    /// Sets the buffer that state payloads are serialized into. The buffer is
+    /// reused for every publish, so its allocation lives as long as the
+    /// `Device`.
+    #[must_use]
+    pub fn buffer(mut self, buffer: Vec<u8>) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// Sets the maximum size of the serialization buffer, and allocates it up
+    /// front. Serializing a larger state fails with `Error::BufferSize`.
+    /// Values larger than `MAX_PAYLOAD_SIZE` are clamped. Defaults to
+    /// `MAX_PAYLOAD_SIZE`, allocating only as needed.
+    #[inline]
+    #[must_use]
+    pub const fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = Some(size);
+        self
+    }
+
+    /// Sets a state that the broker publishes on the device's behalf if it
+    /// disconnects unexpectedly, e.g. `{ "data": { "online": false } }`. The
+    /// state is sent with `QoS::AtLeastOnce` (1) as the MQTT Last Will and
+    /// Testament.
+    #[must_use]
+    pub fn last_will<S>(mut self, state: &S) -> Self
+    where
+        S: serde::Serialize,
+    {
+        self.last_will = Some(serde_json::to_vec(state));
+        self
+    }
+
+    /// Sets a state that is published every time the device connects, e.g.
+    /// `{ "data": { "online": true } }`, to undo the state set with
+    /// `last_will()` after a reconnect. Defaults to the booleans in the
+    /// `data` of the last will, negated, if it has any.
+    #[must_use]
+    pub fn online_state<S>(mut self, state: &S) -> Self
+    where
+        S: serde::Serialize,
+    {
+        self.online_state = Some(serde_json::to_vec(state));
+        self
+    }
+
+    /// Sets the `Transfer` that enables chunked file uploads with
+    /// `Device::upload()`, and downloads if it has a handler.
+    #[must_use]
+    pub fn transfer(mut self, transfer: Transfer) -> Self {
+        self.transfer = Some(transfer);
+        self
+    }
+
+    /// Sets the `RemoteConfig` that Losant can update with a command. Keep a
+    /// clone of `config` to read the configuration.
+    #[must_use]
+    pub fn remote_config<T>(mut self, config: &RemoteConfig<T>) -> Self
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + 'static,
+    {
+        let config = config.clone();
+        self.remote_config = Some(Box::new(move |client, state_topic| {
+            config.start(client, state_topic)
+        }));
+        self
+    }
+
+    /// Sets the `Health` reporter that periodically publishes device metrics.
+    #[must_use]
+    pub fn health(mut self, health: Health) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Sets the `LogForwarder` that forwards `log` records through the
+    /// device, see `Logs::install()`.
+    #[must_use]
+    pub fn logs(mut self, logs: LogForwarder) -> Self {
+        self.logs = Some(logs);
+        self
+    }
+
+    /// Enable a `Builtin` command, which is handled before any other handler
+    /// for a command of the same name.
+    #[must_use]
+    pub fn builtin(mut self, builtin: Builtin) -> Self {
+        if !self.builtins.contains(&builtin) {
+            self.builtins.push(builtin);
+        }
+        self
+    }
+
+    /// If set `true`, `Device::shutdown()` unsubscribes from all topics before
+    /// disconnecting. Defaults to `false`.
+    #[inline]
+    #[must_use]
+    pub const fn unsubscribe_on_shutdown(mut self, unsubscribe: bool) -> Self {
+        self.unsubscribe_on_shutdown = unsubscribe;
+        self
+    }
+
+    /// If set `true`, the broker keeps the MQTT session while the device is
+    /// offline and delivers the Losant commands it missed when it reconnects,
+    /// e.g. after deep sleep. Defaults to `false`.
+    ///
+    /// Disables clean sessions and subscribes to commands with
+    /// `QoS::AtLeastOnce` (1). The session is tied to the client ID, which is
+    /// always the device ID. Commands that the broker delivers more than once
+    /// are only passed to `command_handler()` once: the last 32 are
+    /// remembered in memory, or in `persistent_session_storage()` if set, so
+    /// that commands redelivered after the device is rebuilt, e.g. after deep
+    /// sleep, are dropped too.
+    #[inline]
+    #[must_use]
+    pub const fn persistent_session(mut self, persistent: bool) -> Self {
+        self.persistent_session = persistent;
+        self
+    }
+
+    /// Sets the storage that the commands remembered by `persistent_session()`
+    /// are persisted to, under the key `losant_recent`. They are stored each
+    /// time a command is received.
+    #[must_use]
+    pub fn persistent_session_storage(mut self, storage: impl Storage + Send + 'static) -> Self {
+        self.session_storage = Some(Box::new(storage));
+        self
+    }
+
+    /// Sets the window in which Losant commands with the same time, name and
+    /// payload as an earlier command are dropped, e.g. when a command is
+    /// redelivered after a reconnect. Commands are only remembered in memory,
+    /// by the `Device` that received them. Disabled by default.
+    #[inline]
+    #[must_use]
+    pub const fn dedup_window(mut self, window: Duration) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    /// Sets the maximum age of Losant commands, by their send time. Older
+    /// commands are dropped. Requires the system time to be set, e.g. with
+    /// SNTP; commands that appear to be from the future are not dropped.
+    /// Disabled by default.
+    #[inline]
+    #[must_use]
+    pub const fn max_command_age(mut self, max_age: Duration) -> Self {
+        self.max_command_age = Some(max_age);
+        self
+    }
+
+    /// Sets the `Ota` that runs firmware updates started by a Losant command.
+    #[cfg(feature = "ota")]
+    #[must_use]
+    pub fn ota<F, D>(mut self, ota: crate::Ota<F, D>) -> Self
+    where
+        F: crate::Firmware + Send + 'static,
+        D: crate::Download + Send + 'static,
+    {
+        self.ota = Some(Box::new(move |client, state_topic| {
+            ota.start(client, state_topic)
+        }));
+        self
+    }
+
+    /// Sets the `Scheduler` that holds commands asking to be run later, and
+    /// dispatches them to the command handlers when due. Every command is then
+    /// handled on the scheduler thread instead of in the MQTT event handler.
+    #[must_use]
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = Some(scheduler);
+        self
+    }
+
     /// Consumes the `Builder` to create a `Device`.
     ///
     /// # Errors
     ///
     /// - if a device ID was not provided
+    /// - if the last will or online state could not be serialized
+    /// - if scheduled commands could not be loaded from storage
+    /// - if recent commands could not be loaded from storage
+    /// - if the scheduler thread could not be started
+    /// - if the firmware update thread could not be started
+    /// - if the remote config could not be loaded from storage
+    /// - if the health reporting thread could not be started
     /// - if the MQTT client could not be constructed
     /// - if the client failed to subscribe to the Losant `command` topic
+    /// - if the client failed to subscribe to the file transfer topics
     #[allow(clippy::missing_panics_doc)]
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip_all, err))]
     pub fn build(self) -> Result<Device<'a>> {
         let mut config = MqttClientConfiguration {
             // https://docs.losant.com/mqtt/overview/#mqtt-version-and-limitations
@@ -211,42 +952,276 @@ where
             config.client_id = Some(crate::CONFIG.losant_device_id);
         }
 
+        if self.persistent_session {
+            config.disable_clean_session = true;
+        }
+
         let mut handler = self.handler.unwrap_or_else(|| Box::new(|_| {}));
         let mut command_handler = self.command_handler.unwrap_or_else(|| Box::new(|_| {}));
-        let (state_topic, command_topic) = Self::topics(config.client_id.ok_or(Error::MissingId)?);
-        let client = EspMqttClient::new(
-            format!("mqtt{}://{BROKER_HOST}", if self.secure { "s" } else { "" }),
-            &config,
-            {
+        let id = config.client_id.ok_or(Error::MissingId)?;
+        let (state_topic, command_topic) = Self::topics(id);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(id, secure = self.secure, "building device");
+        let will = self.last_will.transpose()?;
+        let online_state = match self.online_state.transpose()? {
+            Some(online_state) => Some(online_state),
+            None => will.as_deref().and_then(online_state_for),
+        };
+        let routes = Routes::default();
+        let inflight = Inflight::default();
+        let connection = Connection::default();
+        let metrics = Counters::default();
+        let shared = SharedClient::new(inflight.clone(), metrics.clone());
+        let mut router = self.router;
+        #[cfg(feature = "ota")]
+        let ota = self
+            .ota
+            .map(|start| start(shared.downgrade(), state_topic.clone()))
+            .transpose()?;
+        #[cfg(feature = "ota")]
+        if let Some(ota) = &ota {
+            router.insert(ota.command().to_owned(), ota.route());
+        }
+        let mut report_config = match self.remote_config {
+            Some(start) => {
+                let attached = start(shared.downgrade(), state_topic.clone())?;
+                router.insert(attached.command, attached.route);
+                Some(attached.report)
+            }
+            None => None,
+        };
+        let last_state = self
+            .builtins
+            .contains(&Builtin::ResendState)
+            .then(LastState::default);
+        if !self.builtins.is_empty() {
+            let context = Arc::new(builtin::Context {
+                client: shared.downgrade(),
+                state_topic: state_topic.clone(),
+                started: Instant::now(),
+                metrics: {
+                    let metrics = metrics.clone();
+                    let connection = connection.clone();
+                    let inflight = inflight.clone();
+                    Box::new(move || metrics.snapshot(&connection, &inflight))
+                },
+                last_state: last_state.clone().unwrap_or_default(),
+            });
+            for builtin in &self.builtins {
+                router.insert(builtin.name().to_owned(), builtin.route(context.clone()));
+            }
+        }
+        router.fallback(Box::new(move |command: &Value| {
+            if let Ok(command) = Command::deserialize(command) {
+                command_handler(&command);
+            }
+        }));
+        let dispatch: Dispatch = Box::new(move |command: &Value| {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!("command", name = command["name"].as_str()).entered();
+            router.dispatch(command);
+        });
+        let mut recent = if self.persistent_session {
+            Some(Recent::new(RECENT_COMMANDS, self.session_storage)?)
+        } else {
+            None
+        };
+        // the scheduler thread takes over the handlers, so the event handler
+        // never waits on a handler that is publishing
+        let (scheduler, mut dispatch) = match self.scheduler {
+            Some(scheduler) => (Some(scheduler.start(dispatch)?), None),
+            None => (None, Some(dispatch)),
+        };
+        let url = format!("mqtt{}://{BROKER_HOST}", if self.secure { "s" } else { "" });
+        // ESP-MQTT copies the will when the client is constructed, so it is
+        // only borrowed here, and `Device` keeps its own copy for `shutdown()`
+        let mut client_config: MqttClientConfiguration<'_> = config.clone();
+        if let Some(will) = &will {
+            client_config.lwt = Some(LwtConfiguration {
+                topic: &state_topic,
+                payload: will,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+            });
+        }
+        let client = EspMqttClient::new(&url, &client_config, {
+            let state_topic = state_topic.clone();
+            let command_topic = command_topic.clone();
+            let client = shared.downgrade();
+            let routes = routes.clone();
+            let inflight = inflight.clone();
+            let connection = connection.clone();
+            let metrics = metrics.clone();
+            let logs = self.logs.clone();
+            let schedule = scheduler.as_ref().map(|worker| worker.schedule().clone());
+            let mut replay = (self.dedup_window.is_some() || self.max_command_age.is_some())
+                .then(|| Replay::new(self.dedup_window, self.max_command_age));
+            let mut fragments = Fragments::default();
+            // handle a whole message on a topic of the device, returning
+            // `false` if nothing did
+            let mut receive = {
                 let command_topic = command_topic.clone();
-                move |event| {
-                    if let Ok(Event::Connected(_)) = event {}
-
-                    if let Ok(Event::Received(msg)) = event {
-                        if let Some(topic) = msg.topic() {
-                            if topic == &*command_topic {
-                                if let Ok(command) = serde_json::from_slice::<Command>(msg.data()) {
-                                    {
-                                        command_handler(&command);
-                                    }
-                                }
-
-                                return;
-                            }
+                let routes = routes.clone();
+                move |topic: &str, id: MessageId, data: &[u8]| {
+                    if topic != command_topic {
+                        return routes.dispatch(topic, data);
+                    }
+
+                    let command = serde_json::from_slice::<Value>(data);
+                    let accepted = command.as_ref().map_or(false, |command| {
+                        recent
+                            .as_mut()
+                            .map_or(true, |recent| recent.insert(crate::dedup::key((id, data))))
+                            && replay.as_mut().map_or(true, |r| r.accept(command))
+                    });
+                    metrics.command(command.is_ok(), accepted);
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(
+                        name = command.as_ref().ok().and_then(|c| c["name"].as_str()),
+                        parsed = command.is_ok(),
+                        accepted,
+                        "received command"
+                    );
+
+                    if let (Ok(command), true) = (command, accepted) {
+                        if let Some(schedule) = &schedule {
+                            schedule.submit(command);
+                        } else if let Some(dispatch) = &mut dispatch {
+                            dispatch(&command);
                         }
                     }
 
-                    handler(event);
+                    true
                 }
-            },
-        )?;
+            };
+            move |event| {
+                if let (Ok(Event::Connected(_)), Some(online_state)) = (event, &online_state) {
+                    client.defer(Deferred {
+                        topic: state_topic.clone(),
+                        qos: QoS::AtLeastOnce,
+                        retain: false,
+                        payload: online_state.clone(),
+                    });
+                }
+
+                #[cfg(feature = "ota")]
+                if let (Ok(Event::Connected(_)), Some(ota)) = (event, &ota) {
+                    ota.connected();
+                }
+                if let (Ok(Event::Connected(_)), Some(report)) = (event, &mut report_config) {
+                    report();
+                }
+
+                match event {
+                    Ok(Event::Connected(_)) => connection.set(true),
+                    Ok(Event::Disconnected) => connection.set(false),
+                    Ok(Event::Published(id)) => inflight.complete(*id, Delivery::Acknowledged),
+                    Ok(Event::Deleted(id)) => inflight.complete(*id, Delivery::Deleted),
+                    _ => {}
+                }
+                if let (Ok(Event::Connected(_)), Some(logs)) = (event, &logs) {
+                    logs.connected();
+                }
+
+                if let Ok(Event::Received(msg)) = event {
+                    let handled = match fragments.receive(
+                        msg.topic().as_deref(),
+                        msg.details(),
+                        msg.data(),
+                        |topic| topic == command_topic || routes.contains(topic),
+                    ) {
+                        Fragment::Other => {
+                            matches!(msg.details(), Details::Complete)
+                                && msg
+                                    .topic()
+                                    .as_deref()
+                                    .map_or(false, |topic| receive(topic, msg.id(), msg.data()))
+                        }
+                        Fragment::Partial => true,
+                        Fragment::Complete(topic, data) => {
+                            receive(&topic, msg.id(), &data);
+                            true
+                        }
+                    };
+                    if handled {
+                        return;
+                    }
+                }
+
+                handler(event);
+            }
+        })
+        .map_err(Error::client(Operation::Connect, &url))?;
+        let health = self
+            .health
+            .map(|health| health.start(shared.downgrade(), connection.clone(), state_topic.clone()))
+            .transpose()?;
+        let mut buf = self.buffer.unwrap_or_default();
+        let buf_limit = self
+            .buffer_size
+            .map_or(MAX_PAYLOAD_SIZE, |size| size.min(MAX_PAYLOAD_SIZE));
+        if self.buffer_size.is_some() {
+            buf.clear();
+            buf.reserve_exact(buf_limit);
+        }
+
+        shared.set(client);
+        if let Some(logs) = &self.logs {
+            logs.attach(shared.downgrade(), connection.clone(), &state_topic);
+        }
         let mut device = Device {
             state_topic,
+            command_topic: command_topic.clone(),
             config,
-            client,
+            client: shared,
+            will,
+            buf,
+            buf_limit,
+            routes,
+            inflight,
+            connection,
+            metrics,
+            last_state,
+            subscriptions: Vec::new(),
+            unsubscribe_on_shutdown: self.unsubscribe_on_shutdown,
+            _scheduler: scheduler,
+            uploads: None,
+            _health: health,
         };
 
-        device.subscribe(command_topic)?;
+        let command_qos = if self.persistent_session {
+            QoS::AtLeastOnce
+        } else {
+            QoS::AtMostOnce
+        };
+        device.subscribe(command_topic, command_qos)?;
+
+        if let Some(transfer) = self.transfer {
+            let (uploads, downloads) = transfer.split(id);
+            device.subscribe_raw(
+                uploads.ack_filter(),
+                QoS::AtLeastOnce,
+                Box::new(uploads.ack_handler()),
+            )?;
+            if let Some(mut downloads) = downloads {
+                let client = device.client.downgrade();
+                device.subscribe_raw(
+                    downloads.filter(),
+                    QoS::AtLeastOnce,
+                    Box::new(move |topic, data| {
+                        if let Some((topic, payload)) = downloads.receive(topic, data) {
+                            client.defer(Deferred {
+                                topic,
+                                qos: QoS::AtLeastOnce,
+                                retain: false,
+                                payload,
+                            });
+                        }
+                    }),
+                )?;
+            }
+            device.uploads = Some(uploads);
+        }
 
         Ok(device)
     }
@@ -257,3 +1232,20 @@ where
         (format!("losant/{id}/state"), format!("losant/{id}/command"))
     }
 }
+
+/// The state that undoes the last will `will`: the booleans in its `data`,
+/// negated, e.g. `{ "data": { "online": true } }` for a will of
+/// `{ "data": { "online": false } }`. Returns `None` if there are none.
+fn online_state_for(will: &[u8]) -> Option<Vec<u8>> {
+    let will = serde_json::from_slice::<Value>(will).ok()?;
+    let data = will["data"]
+        .as_object()?
+        .iter()
+        .filter_map(|(key, value)| Some((key.clone(), Value::Bool(!value.as_bool()?))))
+        .collect::<serde_json::Map<_, _>>();
+    if data.is_empty() {
+        return None;
+    }
+
+    serde_json::to_vec(&serde_json::json!({ "data": data })).ok()
+}
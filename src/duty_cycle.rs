@@ -0,0 +1,259 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_svc::mqtt::client::QoS;
+
+use crate::client::Client;
+use crate::device::Builder;
+use crate::{Result, Storage};
+
+const PENDING_KEY: &str = "losant_pending";
+const FAILURES_KEY: &str = "losant_failures";
+
+/// The outcome of one wake-publish-sleep cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycle {
+    /// Whether the device connected to the broker.
+    pub connected: bool,
+    /// The number of pending states that were published and acknowledged.
+    pub published: usize,
+    /// The number of states still pending for the next cycle.
+    pub pending: usize,
+    /// The number of pending states that were dropped because they can never
+    /// be published, i.e. they are larger than the device's buffer.
+    pub dropped: usize,
+    /// How long the device should sleep before the next cycle.
+    pub sleep: Duration,
+}
+
+/// A runner for battery powered devices that wake, publish, and sleep.
+///
+/// States queued with `queue()` are retained in `Storage` (e.g. NVS) until
+/// they are acknowledged by the broker, so readings taken while the network is
+/// unavailable survive deep sleep. Each `run()` connects, publishes pending
+/// states, waits briefly for queued Losant commands, disconnects, and reports
+/// how long to sleep. Entering deep sleep is left to the application.
+///
+/// Commands sent while the device was asleep are only delivered if the device
+/// is built with `Builder::persistent_session()`, and redelivered commands are
+/// only dropped across runs with `Builder::persistent_session_storage()`.
+pub struct DutyCycle<St> {
+    storage: St,
+    interval: Duration,
+    max_backoff: Duration,
+    connect_timeout: Duration,
+    ack_timeout: Duration,
+    command_window: Duration,
+    max_pending: usize,
+}
+
+impl<St> DutyCycle<St>
+where
+    St: Storage,
+{
+    /// Create a `DutyCycle` that retains pending states in `storage`.
+    #[inline]
+    #[must_use]
+    pub const fn new(storage: St) -> Self {
+        Self {
+            storage,
+            interval: Duration::from_secs(300),
+            max_backoff: Duration::from_secs(3600),
+            connect_timeout: Duration::from_secs(10),
+            ack_timeout: Duration::from_secs(5),
+            command_window: Duration::from_secs(2),
+            max_pending: 100,
+        }
+    }
+
+    /// Sets the sleep duration after a successful cycle. Defaults to 5
+    /// minutes.
+    #[inline]
+    #[must_use]
+    pub const fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the maximum sleep duration after consecutive failed cycles, which
+    /// double the interval each time. Defaults to 1 hour.
+    #[inline]
+    #[must_use]
+    pub const fn max_backoff(mut self, max: Duration) -> Self {
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets how long to wait for a connection to the broker. Defaults to 10
+    /// seconds.
+    #[inline]
+    #[must_use]
+    pub const fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to wait for published states to be acknowledged.
+    /// Defaults to 5 seconds.
+    #[inline]
+    #[must_use]
+    pub const fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// Sets how long to stay connected after publishing, to receive commands
+    /// queued by the broker. Defaults to 2 seconds.
+    #[inline]
+    #[must_use]
+    pub const fn command_window(mut self, window: Duration) -> Self {
+        self.command_window = window;
+        self
+    }
+
+    /// Sets the maximum number of retained states. The oldest states are
+    /// dropped first. Defaults to 100.
+    #[inline]
+    #[must_use]
+    pub const fn max_pending(mut self, max: usize) -> Self {
+        self.max_pending = max;
+        self
+    }
+
+    /// Retain a state to be published on the next `run()`.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error serializing `state`
+    /// - if there was an error reading or writing storage
+    pub fn queue<S>(&mut self, state: &S) -> Result<()>
+    where
+        S: serde::Serialize,
+    {
+        let mut pending = self.pending()?;
+        pending.push(serde_json::to_value(state)?);
+        if pending.len() > self.max_pending {
+            pending.drain(..pending.len() - self.max_pending);
+        }
+
+        self.store_pending(&pending)
+    }
+
+    /// Run one cycle: build and connect the device, publish pending states,
+    /// wait for commands, and shut down.
+    ///
+    /// Failing to connect or a transient error publishing (see
+    /// `Error::is_transient()`) is not an error; the states stay pending and
+    /// the returned sleep duration backs off. Pending states larger than the
+    /// device's buffer are dropped, so they don't fail every later cycle (see
+    /// `Cycle::dropped`).
+    ///
+    /// # Errors
+    ///
+    /// - if the device could not be built
+    /// - if there was a permanent error publishing pending states, after
+    ///   shutting down the device
+    /// - if there was a permanent error shutting down the device
+    /// - if there was an error reading or writing storage
+    pub fn run<Command>(&mut self, builder: Builder<'_, Command>) -> Result<Cycle>
+    where
+        Command: for<'de> serde::Deserialize<'de> + 'static,
+    {
+        let mut device = builder.build()?;
+        let mut pending = self.pending()?;
+
+        // like `Device::send_states()`, with room for the brackets of an array
+        let limit = device.buf_limit().saturating_sub(2);
+        let mut buf = Vec::new();
+        let count = pending.len();
+        pending.retain(|state| crate::serde::to_json_buf(&mut buf, limit, state).is_ok());
+        let dropped = count - pending.len();
+        if dropped > 0 {
+            self.store_pending(&pending)?;
+        }
+
+        if !device.wait_connected(self.connect_timeout) {
+            return Ok(Cycle {
+                connected: false,
+                published: 0,
+                pending: pending.len(),
+                dropped,
+                sleep: self.fail()?,
+            });
+        }
+
+        let deadline = Instant::now() + self.ack_timeout;
+        let acked = match device.send_states(QoS::AtLeastOnce, false, &pending) {
+            Ok(ids) => ids.into_iter().all(|id| {
+                device.wait_for_ack(id, deadline.saturating_duration_since(Instant::now()))
+            }),
+            // e.g. disconnected again, so back off like a failed connect
+            Err(e) if e.is_transient() => false,
+            Err(e) => {
+                device.shutdown(self.ack_timeout).ok();
+                return Err(e);
+            }
+        };
+        if acked {
+            self.storage.remove(PENDING_KEY)?;
+        }
+
+        thread::sleep(self.command_window);
+        // after a transient error the states are still acknowledged or pending
+        device.shutdown(self.ack_timeout).or_else(|e| {
+            if e.is_transient() {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        })?;
+
+        let (published, sleep) = if acked {
+            self.storage.remove(FAILURES_KEY)?;
+            (pending.len(), self.interval)
+        } else {
+            (0, self.fail()?)
+        };
+
+        Ok(Cycle {
+            connected: true,
+            published,
+            pending: pending.len() - published,
+            dropped,
+            sleep,
+        })
+    }
+
+    /// The states retained for the next cycle.
+    fn pending(&mut self) -> Result<Vec<serde_json::Value>> {
+        Ok(match self.storage.load(PENDING_KEY)? {
+            Some(pending) => serde_json::from_slice(&pending)?,
+            None => Vec::new(),
+        })
+    }
+
+    fn store_pending(&mut self, pending: &[serde_json::Value]) -> Result<()> {
+        if pending.is_empty() {
+            self.storage.remove(PENDING_KEY)
+        } else {
+            self.storage
+                .store(PENDING_KEY, &serde_json::to_vec(pending)?)
+        }
+    }
+
+    /// Record a failed cycle and return the backed off sleep duration.
+    fn fail(&mut self) -> Result<Duration> {
+        let failures = self
+            .storage
+            .load(FAILURES_KEY)?
+            .and_then(|failures| failures.try_into().ok())
+            .map_or(0, u32::from_le_bytes)
+            .saturating_add(1);
+        self.storage.store(FAILURES_KEY, &failures.to_le_bytes())?;
+
+        Ok(self
+            .interval
+            .saturating_mul(2_u32.saturating_pow(failures))
+            .min(self.max_backoff))
+    }
+}
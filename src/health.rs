@@ -0,0 +1,337 @@
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use embedded_svc::mqtt::client::QoS;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::reset::ResetReason;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::timer::EspTaskTimerService;
+use serde_json::{Map, Value};
+
+use crate::connection::Connection;
+use crate::shared::{Deferred, WeakClient};
+use crate::{Result, State};
+
+/// Platform metrics for the health reporter. Every metric defaults to
+/// unavailable, and unavailable metrics are not reported, so only the ones
+/// the platform provides need to be implemented, e.g. from
+/// `esp_idf_svc::wifi::EspWifi` or `esp_idf_hal::reset`.
+pub trait HealthSource: Send {
+    /// The time since boot, e.g. from `EspTaskTimerService::now()` in
+    /// `esp_idf_svc::timer`.
+    fn uptime(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// The free heap in bytes.
+    fn free_heap(&mut self) -> Option<u32> {
+        None
+    }
+
+    /// The lowest free heap since boot, in bytes.
+    fn min_free_heap(&mut self) -> Option<u32> {
+        None
+    }
+
+    /// The Wi-Fi signal strength in dBm.
+    fn rssi(&mut self) -> Option<i8> {
+        None
+    }
+
+    /// Why the device last reset.
+    fn reset_reason(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// Reports only the metrics measured by the `Device` itself.
+impl HealthSource for () {}
+
+/// The metrics ESP-IDF provides without a driver handle: the uptime, from
+/// `EspTaskTimerService`, and the reason for the last reset, e.g. `Brownout`.
+/// The default source of `Health`.
+///
+/// The free heap is not reported, because it can't be read without `unsafe`,
+/// nor is the Wi-Fi signal strength, which needs the `EspWifi`; implement
+/// `HealthSource` to add them.
+#[cfg(target_os = "espidf")]
+pub struct EspHealth {
+    timer: Option<EspTaskTimerService>,
+    reset_reason: String,
+}
+
+#[cfg(target_os = "espidf")]
+impl Default for EspHealth {
+    fn default() -> Self {
+        Self {
+            timer: EspTaskTimerService::new().ok(),
+            reset_reason: format!("{:?}", ResetReason::get()),
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl HealthSource for EspHealth {
+    fn uptime(&mut self) -> Option<Duration> {
+        self.timer.as_ref().map(EspTaskTimerService::now)
+    }
+
+    fn reset_reason(&mut self) -> Option<String> {
+        Some(self.reset_reason.clone())
+    }
+}
+
+/// A metric reported by `Health`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Seconds since boot, see `HealthSource::uptime()`. Defaults to `uptime`.
+    Uptime,
+    /// Defaults to `freeHeap`.
+    FreeHeap,
+    /// Defaults to `minFreeHeap`.
+    MinFreeHeap,
+    /// Defaults to `rssi`.
+    Rssi,
+    /// Defaults to `resetReason`.
+    ResetReason,
+    /// Defaults to `firmwareVersion`.
+    FirmwareVersion,
+    /// Reconnects to the broker since the device was built. Defaults to
+    /// `reconnects`.
+    Reconnects,
+}
+
+impl Metric {
+    const fn default_name(self) -> &'static str {
+        match self {
+            Self::Uptime => "uptime",
+            Self::FreeHeap => "freeHeap",
+            Self::MinFreeHeap => "minFreeHeap",
+            Self::Rssi => "rssi",
+            Self::ResetReason => "resetReason",
+            Self::FirmwareVersion => "firmwareVersion",
+            Self::Reconnects => "reconnects",
+        }
+    }
+}
+
+/// A health reporter that periodically publishes device metrics while
+/// connected, e.g.
+/// `{ "data": { "uptime": 3600, "freeHeap": 120000, "rssi": -60, "reconnects": 2 } }`.
+///
+/// Metrics are published as Losant state, or as a bare JSON object on a
+/// custom topic if `topic()` is set. Reports are sent from a separate thread.
+pub struct Health {
+    source: Box<dyn HealthSource>,
+    interval: Duration,
+    version: Option<String>,
+    names: Vec<(Metric, String)>,
+    topic: Option<String>,
+    stack_size: usize,
+}
+
+#[cfg(target_os = "espidf")]
+impl Default for Health {
+    fn default() -> Self {
+        Self::new(EspHealth::default())
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl Default for Health {
+    fn default() -> Self {
+        Self::new(())
+    }
+}
+
+impl Health {
+    /// Create a `Health` reporter with platform metrics from `source`.
+    #[must_use]
+    pub fn new(source: impl HealthSource + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+            interval: Duration::from_secs(60),
+            version: None,
+            names: Vec::new(),
+            topic: None,
+            stack_size: 4096,
+        }
+    }
+
+    /// Sets how often metrics are published. Defaults to 1 minute.
+    #[inline]
+    #[must_use]
+    pub const fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the reported firmware version, e.g. `env!("CARGO_PKG_VERSION")`.
+    #[must_use]
+    pub fn firmware_version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Sets the attribute name of `metric`.
+    #[must_use]
+    pub fn name(mut self, metric: Metric, name: impl Into<String>) -> Self {
+        self.names.retain(|(m, _)| *m != metric);
+        self.names.push((metric, name.into()));
+        self
+    }
+
+    /// Publish metrics on a custom topic instead of the state topic.
+    #[must_use]
+    pub fn topic(mut self, topic: impl Into<String>) -> Self {
+        self.topic = Some(topic.into());
+        self
+    }
+
+    /// Sets the stack size of the reporting thread. Defaults to 4KB.
+    #[inline]
+    #[must_use]
+    pub const fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Start the reporting thread.
+    pub(crate) fn start(
+        self,
+        client: WeakClient,
+        connection: Connection,
+        state_topic: String,
+    ) -> Result<Worker> {
+        let stop = Stop::default();
+        let thread = thread::Builder::new()
+            .name("losant-health".to_owned())
+            .stack_size(self.stack_size)
+            .spawn({
+                let stop = stop.clone();
+                move || self.run(&stop, &client, &connection, state_topic)
+            })?;
+
+        Ok(Worker {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(
+        mut self,
+        stop: &Stop,
+        client: &WeakClient,
+        connection: &Connection,
+        state_topic: String,
+    ) {
+        let (topic, bare) = match self.topic.take() {
+            Some(topic) => (topic, true),
+            None => (state_topic, false),
+        };
+
+        while !stop.wait(self.interval) {
+            if !connection.is_connected() {
+                continue;
+            }
+
+            let data = self.collect(connection);
+            let payload = if bare {
+                serde_json::to_vec(&data)
+            } else {
+                serde_json::to_vec(&State {
+                    data,
+                    time: None,
+                    flow_version: None,
+                    meta: None,
+                })
+            };
+            if let Ok(payload) = payload {
+                client.defer(Deferred {
+                    topic: topic.clone(),
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    payload,
+                });
+            }
+        }
+    }
+
+    fn collect(&mut self, connection: &Connection) -> Map<String, Value> {
+        let metrics = [
+            (
+                Metric::Uptime,
+                self.source.uptime().map(|uptime| uptime.as_secs().into()),
+            ),
+            (Metric::FreeHeap, self.source.free_heap().map(Value::from)),
+            (
+                Metric::MinFreeHeap,
+                self.source.min_free_heap().map(Value::from),
+            ),
+            (Metric::Rssi, self.source.rssi().map(Value::from)),
+            (
+                Metric::ResetReason,
+                self.source.reset_reason().map(Value::from),
+            ),
+            (
+                Metric::FirmwareVersion,
+                self.version.clone().map(Value::from),
+            ),
+            (Metric::Reconnects, Some(connection.reconnects().into())),
+        ];
+
+        metrics
+            .into_iter()
+            .filter_map(|(metric, value)| {
+                let name = self
+                    .names
+                    .iter()
+                    .find(|(m, _)| *m == metric)
+                    .map_or(metric.default_name(), |(_, name)| name.as_str());
+                Some((name.to_owned(), value?))
+            })
+            .collect()
+    }
+}
+
+/// Wakes the reporting thread to stop it.
+#[derive(Clone, Default)]
+struct Stop(Arc<(Mutex<bool>, Condvar)>);
+
+impl Stop {
+    /// Block until stopped or `timeout` elapses. Returns `true` if stopped.
+    fn wait(&self, timeout: Duration) -> bool {
+        let (stopped, changed) = &*self.0;
+        let (stopped, _) = changed
+            .wait_timeout_while(
+                stopped.lock().unwrap_or_else(PoisonError::into_inner),
+                timeout,
+                |stopped| !*stopped,
+            )
+            .unwrap_or_else(PoisonError::into_inner);
+
+        *stopped
+    }
+
+    fn stop(&self) {
+        let (stopped, changed) = &*self.0;
+        *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        changed.notify_all();
+    }
+}
+
+/// The reporting thread, stopped when dropped.
+pub(crate) struct Worker {
+    stop: Stop,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop.stop();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
@@ -9,26 +9,82 @@
 #![feature(trait_alias)]
 #![doc = include_str!("../README.md")]
 
+use std::fmt;
+use std::io::ErrorKind;
 use std::time::Duration;
 
 use esp_idf_sys::EspError;
 
+mod ack;
+mod batch;
+mod blob;
+mod builtin;
 pub mod client;
+pub mod codec;
+mod command;
+mod connection;
+mod dedup;
 mod device;
+mod duty_cycle;
+mod health;
+mod logs;
+mod metrics;
+#[cfg(feature = "ota")]
+mod ota;
+mod remote_config;
+mod schedule;
 pub mod serde;
+mod shared;
+mod storage;
+mod topic;
+mod transfer;
 
-pub use crate::device::{CommandHandler, ConfigUpdater, Device, EventResult, EventResultHandler};
+pub use crate::ack::Delivery;
+pub use crate::batch::Batch;
+pub use crate::blob::{Blob, MAX_BLOB_SIZE};
+pub use crate::builtin::Builtin;
+pub use crate::command::Command;
+pub use crate::device::{
+    AckHandler, CommandHandler, ConfigUpdater, Device, EventResult, EventResultHandler,
+    TopicHandler,
+};
+pub use crate::duty_cycle::{Cycle, DutyCycle};
+#[cfg(target_os = "espidf")]
+pub use crate::health::EspHealth;
+pub use crate::health::{Health, HealthSource, Metric};
+pub use crate::logs::{LogForwarder, Logs};
+pub use crate::metrics::{Failures, Metrics};
+#[cfg(feature = "ota")]
+pub use crate::ota::{
+    Download, Firmware, FirmwareUpdate, FirmwareWriter, Ota, OtaReport, OtaStatus,
+};
+pub use crate::remote_config::{ConfigValidator, RemoteConfig};
+pub use crate::schedule::Scheduler;
+pub use crate::storage::Storage;
+pub use crate::transfer::{Manifest, Transfer, TransferHandler};
 
 pub mod prelude {
     pub use serde_json::json;
 
+    pub use crate::ack::Delivery;
+    pub use crate::batch::Batch;
+    pub use crate::blob::Blob;
     pub use crate::client::Client as _;
+    pub use crate::codec::Codec as _;
     pub use crate::device::{
-        CommandHandler, ConfigUpdater, Device, EventResult, EventResultHandler,
+        AckHandler, CommandHandler, ConfigUpdater, Device, EventResult, EventResultHandler,
+        TopicHandler,
     };
+    pub use crate::remote_config::ConfigValidator;
+    pub use crate::transfer::TransferHandler;
     pub use crate::State;
 }
 
+/// The maximum size of a message payload accepted by the Losant broker.
+///
+/// See <https://docs.losant.com/mqtt/overview/#message-limits>
+pub const MAX_PAYLOAD_SIZE: usize = 256_000;
+
 #[toml_cfg::toml_config]
 struct Config {
     #[default("")]
@@ -43,8 +99,35 @@ struct Config {
 pub enum Error {
     #[error(transparent)]
     Esp(#[from] EspError),
+    /// An MQTT client call failed. For `Operation::Connect`, `topic` is the
+    /// broker URL.
+    #[error("{operation} `{topic}` failed: {source}")]
+    Client {
+        operation: Operation,
+        topic: String,
+        source: EspError,
+    },
+    #[cfg(feature = "ota")]
+    #[error(transparent)]
+    EspIo(#[from] esp_idf_svc::errors::EspIOError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error(transparent)]
     Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Logger(#[from] log::SetLoggerError),
+    #[cfg(feature = "cbor")]
+    #[error(transparent)]
+    CborEncode(#[from] ciborium::ser::Error<std::io::Error>),
+    #[cfg(feature = "cbor")]
+    #[error(transparent)]
+    CborDecode(#[from] ciborium::de::Error<std::io::Error>),
+    #[cfg(feature = "msgpack")]
+    #[error(transparent)]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[cfg(feature = "msgpack")]
+    #[error(transparent)]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
     #[error("a device ID was not provided")]
     MissingId,
     #[error(
@@ -53,9 +136,113 @@ pub enum Error {
     QoS2NotSupported,
     #[error("payload exceeded maximum size of 256KB")]
     PayloadSize,
+    #[error("blob exceeded maximum size of 255KB")]
+    BlobSize,
+    #[error("payload exceeded the size of the serialization buffer")]
+    BufferSize,
+    #[error("invalid topic filter `{topic}`: {reason}")]
+    InvalidTopic { topic: String, reason: &'static str },
+    #[error("file transfer failed: {0}")]
+    Transfer(&'static str),
+    #[cfg(feature = "ota")]
+    #[error("invalid firmware update: {0}")]
+    InvalidUpdate(&'static str),
+    #[cfg(feature = "ota")]
+    #[error("firmware download failed with HTTP status {0}")]
+    HttpStatus(u16),
+    #[cfg(feature = "ota")]
+    #[error("firmware image did not match its SHA-256 digest")]
+    Checksum,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// Whether the error is likely to go away by itself, e.g. while
+    /// disconnected or while the outbox is full, so the call can be retried
+    /// later. Every other error is permanent, e.g. an invalid QoS, an
+    /// oversized payload, a serialization error, running out of memory, or
+    /// failing to construct the client, and retrying the same call fails the
+    /// same way.
+    #[must_use]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            // constructing the client fails on invalid configuration, not on
+            // network conditions, which it retries by itself
+            Self::Client {
+                operation: Operation::Connect,
+                ..
+            } => false,
+            Self::Esp(e) | Self::Client { source: e, .. } => is_transient_esp(e),
+            #[cfg(feature = "ota")]
+            Self::EspIo(e) => is_transient_esp(&e.0),
+            Self::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::Interrupted
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::NotConnected
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+            ),
+            #[cfg(feature = "ota")]
+            Self::HttpStatus(status) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Wrap an error from the MQTT client with the `operation` and `topic`.
+    pub(crate) fn client(operation: Operation, topic: &str) -> impl FnOnce(EspError) -> Self + '_ {
+        move |source| Self::Client {
+            operation,
+            topic: topic.to_owned(),
+            source,
+        }
+    }
+}
+
+/// Whether an ESP-IDF error is a temporary condition. ESP-MQTT returns -1
+/// from publish calls while disconnected, and -2 while the outbox is full.
+/// Running out of memory is not, as retrying only holds on to more of it.
+fn is_transient_esp(e: &EspError) -> bool {
+    const OUTBOX_FULL: i64 = -2;
+    let code = i64::from(e.code());
+    [
+        OUTBOX_FULL,
+        i64::from(esp_idf_sys::ESP_FAIL),
+        i64::from(esp_idf_sys::ESP_ERR_TIMEOUT),
+        i64::from(esp_idf_sys::ESP_ERR_INVALID_STATE),
+    ]
+    .contains(&code)
+}
+
+/// An MQTT client call, see `Error::Client`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Constructing the client, which starts connecting to the broker.
+    Connect,
+    /// `Client::publish()`, and the methods that publish state.
+    Publish,
+    /// `Client::enqueue()`.
+    Enqueue,
+    /// `Client::subscribe()`.
+    Subscribe,
+    /// `Client::unsubscribe()`.
+    Unsubscribe,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Connect => "connect to",
+            Self::Publish => "publish to",
+            Self::Enqueue => "enqueue to",
+            Self::Subscribe => "subscribe to",
+            Self::Unsubscribe => "unsubscribe from",
+        })
+    }
+}
+
 /// A serializable Losant `state` topic message. For the `time` field, see
 /// `esp_idf_svc::systime::EspSystemTime::now()`.
 ///
@@ -0,0 +1,420 @@
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use embedded_svc::mqtt::client::QoS;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::connection::Connection;
+use crate::shared::{Deferred, WeakClient};
+use crate::{Result, State, MAX_BLOB_SIZE};
+
+/// Clocks earlier than this (2020-01-01) have not been set, e.g. by SNTP, so
+/// records are left for Losant to timestamp when they arrive.
+const CLOCK_SET: Duration = Duration::from_secs(1_577_836_800);
+
+thread_local! {
+    /// Set while forwarding records, so that records logged by the MQTT
+    /// client itself are not forwarded in a loop.
+    static FORWARDING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Where forwarded records are published.
+enum Output {
+    /// As a line of text in a string attribute of the device state.
+    Attribute(String),
+    /// As a JSON object on a custom topic.
+    Topic(String),
+}
+
+/// Settings for a `LogForwarder`, which forwards `log` records at or above
+/// `level` to Losant, e.g. as a `log` attribute of
+/// `{ "data": { "log": "WARN app::sensor: read timed out" } }`.
+///
+/// Records are kept in a ring buffer until the device is built and connected,
+/// and while it is disconnected, and are dumped when it (re)connects. The
+/// oldest records are dropped when the buffer is full, and records over the
+/// rate limit are dropped; both are reported in a record of their own.
+pub struct Logs {
+    level: LevelFilter,
+    inner: Option<Box<dyn Log>>,
+    output: Output,
+    max_length: usize,
+    rate: (u32, Duration),
+    capacity: usize,
+}
+
+impl Logs {
+    /// Create `Logs` that forward records at or above `level`.
+    #[must_use]
+    pub fn new(level: Level) -> Self {
+        Self {
+            level: level.to_level_filter(),
+            inner: None,
+            output: Output::Attribute("log".to_owned()),
+            max_length: 1024,
+            rate: (30, Duration::from_secs(60)),
+            capacity: 32,
+        }
+    }
+
+    /// Sets a logger that every record is also passed to, e.g.
+    /// `esp_idf_svc::log::EspLogger` to keep logging to the serial console.
+    /// It does its own filtering.
+    #[must_use]
+    pub fn inner(mut self, logger: impl Log + 'static) -> Self {
+        self.inner = Some(Box::new(logger));
+        self
+    }
+
+    /// Sets the state attribute that records are published as. Defaults to
+    /// `log`.
+    #[must_use]
+    pub fn attribute(mut self, name: impl Into<String>) -> Self {
+        self.output = Output::Attribute(name.into());
+        self
+    }
+
+    /// Publish records on a custom topic instead of as state, e.g.
+    /// `{ "level": "WARN", "target": "app::sensor", "message": "read timed out" }`.
+    #[must_use]
+    pub fn topic(mut self, topic: impl Into<String>) -> Self {
+        self.output = Output::Topic(topic.into());
+        self
+    }
+
+    /// Sets the length in bytes that messages are truncated to. Defaults to
+    /// 1KB, and is limited to `MAX_BLOB_SIZE`.
+    #[inline]
+    #[must_use]
+    pub const fn max_length(mut self, length: usize) -> Self {
+        self.max_length = if length < MAX_BLOB_SIZE {
+            length
+        } else {
+            MAX_BLOB_SIZE
+        };
+        self
+    }
+
+    /// Sets the maximum number of records forwarded per `period`. Defaults to
+    /// 30 per minute.
+    #[inline]
+    #[must_use]
+    pub const fn rate_limit(mut self, records: u32, period: Duration) -> Self {
+        self.rate = (records, period);
+        self
+    }
+
+    /// Sets how many records are kept while disconnected. Defaults to 32.
+    #[inline]
+    #[must_use]
+    pub const fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Create the `LogForwarder`, e.g. to combine it with other loggers. See
+    /// `install()`.
+    #[must_use]
+    pub fn build(self) -> LogForwarder {
+        LogForwarder(Arc::new(Shared {
+            buffer: Mutex::new(Buffer {
+                attached: None,
+                ring: VecDeque::with_capacity(self.capacity),
+                window: Instant::now(),
+                forwarded: 0,
+                limited: 0,
+                overflowed: 0,
+            }),
+            settings: self,
+        }))
+    }
+
+    /// Create the `LogForwarder` and set it as the global logger. Pass it to
+    /// `Builder::logs()` to start forwarding.
+    ///
+    /// # Errors
+    ///
+    /// - if a global logger was already set
+    pub fn install(self) -> Result<LogForwarder> {
+        let level = if self.inner.is_some() {
+            log::STATIC_MAX_LEVEL
+        } else {
+            self.level
+        };
+        let forwarder = self.build();
+        log::set_boxed_logger(Box::new(forwarder.clone()))?;
+        log::set_max_level(level);
+
+        Ok(forwarder)
+    }
+}
+
+/// A forwarded record.
+struct Entry {
+    level: Level,
+    target: String,
+    message: String,
+    time: SystemTime,
+}
+
+impl Entry {
+    fn new(record: &Record, max_length: usize) -> Self {
+        let mut message = record.args().to_string();
+        if message.len() > max_length {
+            let mut end = max_length;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+
+        Self {
+            level: record.level(),
+            target: record.target().to_owned(),
+            message,
+            time: SystemTime::now(),
+        }
+    }
+
+    /// A record of records that were dropped.
+    fn dropped(count: u64, reason: &str) -> Self {
+        Self {
+            level: Level::Warn,
+            target: module_path!().to_owned(),
+            message: format!("{count} log records dropped ({reason})"),
+            time: SystemTime::now(),
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, if the clock has been set.
+    fn millis(&self) -> Option<u64> {
+        self.time
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .filter(|time| *time >= CLOCK_SET)
+            .and_then(|time| u64::try_from(time.as_millis()).ok())
+    }
+
+    fn payload(&self, output: &Output) -> serde_json::Result<Vec<u8>> {
+        match output {
+            Output::Attribute(name) => serde_json::to_vec(&State {
+                data: HashMap::from([(
+                    name.as_str(),
+                    format!("{} {}: {}", self.level, self.target, self.message),
+                )]),
+                time: self.millis(),
+                flow_version: None,
+                meta: None,
+            }),
+            Output::Topic(_) => serde_json::to_vec(&Line {
+                level: self.level.as_str(),
+                target: &self.target,
+                message: &self.message,
+                time: self.millis(),
+            }),
+        }
+    }
+}
+
+/// A record published on a custom topic.
+#[derive(serde::Serialize)]
+struct Line<'a> {
+    level: &'static str,
+    target: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<u64>,
+}
+
+/// The device that records are forwarded through.
+struct Attached {
+    client: WeakClient,
+    connection: Connection,
+    topic: String,
+}
+
+struct Buffer {
+    attached: Option<Attached>,
+    ring: VecDeque<Entry>,
+    /// When the current rate limit period started.
+    window: Instant,
+    forwarded: u32,
+    /// Records dropped by the rate limit, reported when the period ends.
+    limited: u64,
+    /// Records dropped because the ring buffer was full, reported on the next
+    /// dump.
+    overflowed: u64,
+}
+
+impl Buffer {
+    /// Whether `rate` allows forwarding another record now.
+    fn allow(&mut self, (records, period): (u32, Duration), capacity: usize) -> bool {
+        if self.window.elapsed() >= period {
+            self.window = Instant::now();
+            self.forwarded = 0;
+            if self.limited > 0 {
+                let entry = Entry::dropped(self.limited, "rate limited");
+                self.limited = 0;
+                self.push(entry, capacity);
+            }
+        }
+
+        if self.forwarded < records {
+            self.forwarded += 1;
+            true
+        } else {
+            self.limited += 1;
+            false
+        }
+    }
+
+    fn push(&mut self, entry: Entry, capacity: usize) {
+        if self.ring.len() >= capacity.max(1) {
+            self.ring.pop_front();
+            self.overflowed += 1;
+        }
+        self.ring.push_back(entry);
+    }
+
+    /// Take all buffered records to publish, if connected.
+    fn dump(&mut self) -> Option<Dump> {
+        let attached = self.attached.as_ref()?;
+        if !attached.connection.is_connected() || (self.ring.is_empty() && self.overflowed == 0) {
+            return None;
+        }
+
+        if self.overflowed > 0 {
+            let entry = Entry::dropped(self.overflowed, "buffer full");
+            self.overflowed = 0;
+            self.ring.push_front(entry);
+        }
+
+        Some(Dump {
+            client: attached.client.clone(),
+            topic: attached.topic.clone(),
+            entries: self.ring.drain(..).collect(),
+        })
+    }
+}
+
+/// Records taken from the buffer, to publish once it is unlocked.
+struct Dump {
+    client: WeakClient,
+    topic: String,
+    entries: Vec<Entry>,
+}
+
+impl Dump {
+    fn publish(self, output: &Output) {
+        for entry in self.entries {
+            if let Ok(payload) = entry.payload(output) {
+                self.client.defer(Deferred {
+                    topic: self.topic.clone(),
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                    payload,
+                });
+            }
+        }
+    }
+}
+
+struct Shared {
+    settings: Logs,
+    buffer: Mutex<Buffer>,
+}
+
+/// A `log::Log` that forwards records to Losant through a `Device`, see
+/// `Logs`.
+///
+/// `LogForwarder` is a cheap handle; clone it to install it and also pass it
+/// to `Builder::logs()`.
+#[derive(Clone)]
+pub struct LogForwarder(Arc<Shared>);
+
+impl LogForwarder {
+    fn buffer(&self) -> MutexGuard<'_, Buffer> {
+        self.0.buffer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` with the buffer, unless this thread is already forwarding, and
+    /// publish the records it takes.
+    ///
+    /// Publishing may wait for the MQTT client, and the event handler locks the
+    /// buffer while the client waits for it, so records are only published
+    /// after the buffer is unlocked.
+    fn forwarding(&self, f: impl FnOnce(&mut Buffer) -> Option<Dump>) {
+        if FORWARDING.with(|forwarding| forwarding.replace(true)) {
+            return;
+        }
+
+        let dump = f(&mut self.buffer());
+        if let Some(dump) = dump {
+            dump.publish(&self.0.settings.output);
+        }
+        FORWARDING.with(|forwarding| forwarding.set(false));
+    }
+
+    /// Forward records through `client`, and dump the records buffered so
+    /// far.
+    pub(crate) fn attach(&self, client: WeakClient, connection: Connection, state_topic: &str) {
+        let settings = &self.0.settings;
+        let topic = match &settings.output {
+            Output::Attribute(_) => state_topic.to_owned(),
+            Output::Topic(topic) => topic.clone(),
+        };
+
+        self.forwarding(|buffer| {
+            buffer.attached = Some(Attached {
+                client,
+                connection,
+                topic,
+            });
+            buffer.dump()
+        });
+    }
+
+    /// Dump the records buffered while disconnected.
+    pub(crate) fn connected(&self) {
+        self.forwarding(Buffer::dump);
+    }
+}
+
+impl Log for LogForwarder {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let settings = &self.0.settings;
+        metadata.level() <= settings.level
+            || settings
+                .inner
+                .as_ref()
+                .map_or(false, |inner| inner.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        let settings = &self.0.settings;
+        if let Some(inner) = &settings.inner {
+            if inner.enabled(record.metadata()) {
+                inner.log(record);
+            }
+        }
+
+        if record.level() > settings.level {
+            return;
+        }
+
+        self.forwarding(|buffer| {
+            if buffer.allow(settings.rate, settings.capacity) {
+                buffer.push(Entry::new(record, settings.max_length), settings.capacity);
+            }
+            buffer.dump()
+        });
+    }
+
+    fn flush(&self) {
+        if let Some(inner) = &self.0.settings.inner {
+            inner.flush();
+        }
+    }
+}
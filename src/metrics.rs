@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::ack::Inflight;
+use crate::connection::Connection;
+use crate::Error;
+
+/// Publish failures, by kind of error.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Failures {
+    /// Payloads that exceeded a size limit.
+    pub payload_size: u64,
+    /// Values that could not be serialized.
+    pub serialization: u64,
+    /// Invalid topics or QoS.
+    pub invalid: u64,
+    /// Errors from the MQTT client, e.g. while disconnected.
+    pub client: u64,
+    /// Any other error.
+    pub other: u64,
+}
+
+/// A snapshot of how the MQTT client is behaving, see `Device::metrics()`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metrics {
+    /// Messages published or enqueued, including those the crate publishes by
+    /// itself, e.g. health reports and replies to built-in commands.
+    pub published: u64,
+    /// Messages that failed to publish.
+    pub failed: Failures,
+    /// Payload bytes published or enqueued.
+    pub bytes_sent: u64,
+    /// Messages received on the Losant `command` topic.
+    pub commands_received: u64,
+    /// Commands that were valid JSON.
+    pub commands_parsed: u64,
+    /// Commands that were not valid JSON, or were dropped as duplicates or as
+    /// too old.
+    pub commands_rejected: u64,
+    /// Reconnects to the broker since the device was built.
+    pub reconnects: u32,
+    /// QoS 1 messages that have been published but not yet acknowledged by
+    /// the broker, see `Device::unacked()`. This doesn't include QoS 0
+    /// messages still waiting in the ESP-MQTT outbox.
+    pub unacked: usize,
+    /// How long the last publish call took.
+    #[serde(rename = "lastPublishLatencyMs", serialize_with = "as_millis")]
+    pub last_publish_latency: Option<Duration>,
+}
+
+fn as_millis<S>(latency: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serde::Serialize::serialize(&latency.map(|latency| latency.as_millis()), serializer)
+}
+
+/// Metrics counters, shared between a `Device` and its MQTT event handler.
+#[derive(Clone, Default)]
+pub(crate) struct Counters(Arc<Mutex<Metrics>>);
+
+impl Counters {
+    fn metrics(&self) -> MutexGuard<'_, Metrics> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A copy of the current counters, with the reconnects and unacknowledged
+    /// messages at this moment.
+    pub(crate) fn snapshot(&self, connection: &Connection, inflight: &Inflight) -> Metrics {
+        Metrics {
+            reconnects: connection.reconnects(),
+            unacked: inflight.len(),
+            ..self.metrics().clone()
+        }
+    }
+
+    /// Record a published message.
+    pub(crate) fn published(&self, len: usize, latency: Duration) {
+        let mut metrics = self.metrics();
+        metrics.published += 1;
+        metrics.bytes_sent += u64::try_from(len).unwrap_or(u64::MAX);
+        metrics.last_publish_latency = Some(latency);
+    }
+
+    /// Record a failed publish. Returns `e` for chaining.
+    pub(crate) fn failed(&self, e: Error) -> Error {
+        let mut metrics = self.metrics();
+        let failed = &mut metrics.failed;
+        match &e {
+            Error::PayloadSize | Error::BufferSize | Error::BlobSize => failed.payload_size += 1,
+            Error::Json(_) => failed.serialization += 1,
+            #[cfg(feature = "cbor")]
+            Error::CborEncode(_) => failed.serialization += 1,
+            #[cfg(feature = "msgpack")]
+            Error::MessagePackEncode(_) => failed.serialization += 1,
+            Error::QoS2NotSupported | Error::InvalidTopic { .. } => failed.invalid += 1,
+            Error::Esp(_) | Error::Client { .. } => failed.client += 1,
+            _ => failed.other += 1,
+        }
+
+        e
+    }
+
+    /// Record a received command, and whether it was accepted.
+    pub(crate) fn command(&self, parsed: bool, accepted: bool) {
+        let mut metrics = self.metrics();
+        metrics.commands_received += 1;
+        if parsed {
+            metrics.commands_parsed += 1;
+        }
+        if !accepted {
+            metrics.commands_rejected += 1;
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use embedded_svc::http::client::Connection;
+use embedded_svc::http::{Headers, Method, Status};
+use embedded_svc::io::{Read, Write};
+use embedded_svc::mqtt::client::QoS;
+use embedded_svc::ota::{Ota as _, OtaUpdate};
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::command::Route;
+use crate::shared::{Deferred, WeakClient};
+use crate::{Error, Result, State};
+
+const CHUNK_SIZE: usize = 4096;
+/// How long to let the last report go out before restarting.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The payload of the Losant command that starts a firmware update, e.g.
+/// `{ "url": "https://...", "version": "1.2.0", "sha256": "9f86d0..." }`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct FirmwareUpdate {
+    /// The HTTPS URL of the firmware image.
+    pub url: String,
+    /// The version of the firmware image.
+    pub version: String,
+    /// The hex encoded SHA-256 digest of the firmware image.
+    pub sha256: String,
+}
+
+/// The stage of a firmware update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OtaStatus {
+    /// The image is being downloaded and written.
+    Downloading,
+    /// The image has been written and its digest is being checked.
+    Verifying,
+    /// The image was written, and the device is about to restart into it.
+    Updated,
+    /// The update failed, and the running image is unchanged.
+    Failed,
+    /// The requested version is already running.
+    Current,
+    /// The running image was marked valid after a restart, so it will not be
+    /// rolled back.
+    Confirmed,
+}
+
+/// Firmware update progress, published as the `data` of a device state, e.g.
+/// `{ "data": { "otaStatus": "downloading", "otaVersion": "1.2.0", "otaProgress": 40 } }`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OtaReport {
+    /// The stage of the update.
+    pub ota_status: OtaStatus,
+    /// The version being updated to, or the running version once confirmed.
+    pub ota_version: String,
+    /// The percentage of the image written, if its size is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ota_progress: Option<u8>,
+    /// Why the update failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ota_error: Option<String>,
+}
+
+/// The device's application partitions, e.g. `EspOta`.
+pub trait Firmware {
+    type Update<'a>: FirmwareWriter
+    where
+        Self: 'a;
+
+    /// Start writing a new image to the inactive partition.
+    ///
+    /// # Errors
+    ///
+    /// - if the update could not be started
+    fn begin(&mut self) -> Result<Self::Update<'_>>;
+
+    /// Mark the running image as valid, cancelling the rollback to the
+    /// previous image on the next restart.
+    ///
+    /// # Errors
+    ///
+    /// - if the running image could not be marked valid
+    fn confirm(&mut self) -> Result<()>;
+
+    /// Restart into the newly written image.
+    fn restart(&mut self);
+}
+
+/// A firmware image being written to the inactive partition.
+pub trait FirmwareWriter {
+    /// Write the next bytes of the image.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error writing to flash
+    fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Finish the image and boot from it on the next restart.
+    ///
+    /// # Errors
+    ///
+    /// - if the image is invalid or could not be finished
+    fn complete(self) -> Result<()>;
+
+    /// Discard the image.
+    ///
+    /// # Errors
+    ///
+    /// - if the update could not be aborted
+    fn abort(self) -> Result<()>;
+}
+
+/// A source of firmware images, e.g. `EspHttpConnection`.
+pub trait Download {
+    /// Start downloading `url`. Returns the size of the image, if known.
+    ///
+    /// # Errors
+    ///
+    /// - if the request failed or was not successful
+    fn open(&mut self, url: &str) -> Result<Option<usize>>;
+
+    /// Read the next bytes of the image into `buf`. Returns `0` at the end of
+    /// the image.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error reading the response
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+impl Firmware for EspOta {
+    type Update<'a> = EspOtaUpdate<'a>;
+
+    fn begin(&mut self) -> Result<Self::Update<'_>> {
+        Ok(Self::initiate_update(self)?)
+    }
+
+    fn confirm(&mut self) -> Result<()> {
+        Self::mark_running_slot_valid(self)?;
+        Ok(())
+    }
+
+    fn restart(&mut self) {
+        esp_idf_hal::reset::restart();
+    }
+}
+
+impl FirmwareWriter for EspOtaUpdate<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        Write::write_all(self, data)?;
+        Ok(())
+    }
+
+    fn complete(self) -> Result<()> {
+        OtaUpdate::complete(self)?;
+        Ok(())
+    }
+
+    fn abort(self) -> Result<()> {
+        OtaUpdate::abort(self)?;
+        Ok(())
+    }
+}
+
+/// Downloads over HTTPS. The connection must be configured to verify the
+/// server, e.g. with `crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach)`.
+impl Download for EspHttpConnection {
+    fn open(&mut self, url: &str) -> Result<Option<usize>> {
+        Connection::initiate_request(self, Method::Get, url, &[])?;
+        Connection::initiate_response(self)?;
+
+        let status = Status::status(self);
+        if !(200..300).contains(&status) {
+            return Err(Error::HttpStatus(status));
+        }
+
+        Ok(Headers::header(self, "Content-Length").and_then(|len| len.parse().ok()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(Read::read(self, buf)?)
+    }
+}
+
+/// Over-the-air firmware updates, started by a Losant command (see
+/// `command()`) with a `FirmwareUpdate` payload.
+///
+/// The image is downloaded, written to the inactive partition while its
+/// SHA-256 digest is computed, and the device restarts into it if the digest
+/// matches. Progress and the outcome are published as device state (see
+/// `OtaReport`). After restarting, the new image is confirmed once the device
+/// connects to the broker; if it never does, the bootloader rolls back to the
+/// previous image, provided `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE` is set.
+///
+/// Updates run on a separate thread. `update()` and `confirm()` can also be
+/// used directly, e.g. with another `Firmware` or `Download`.
+pub struct Ota<F, D> {
+    firmware: F,
+    download: D,
+    version: String,
+    command: String,
+    stack_size: usize,
+    confirm_on_connect: bool,
+}
+
+impl<F, D> Ota<F, D>
+where
+    F: Firmware,
+    D: Download,
+{
+    /// Create an `Ota` that writes images downloaded with `download` to
+    /// `firmware`. `version` is the running firmware version, e.g.
+    /// `env!("CARGO_PKG_VERSION")`.
+    #[must_use]
+    pub fn new(firmware: F, download: D, version: impl Into<String>) -> Self {
+        Self {
+            firmware,
+            download,
+            version: version.into(),
+            command: "firmwareUpdate".to_owned(),
+            stack_size: 8192,
+            confirm_on_connect: true,
+        }
+    }
+
+    /// Sets the name of the command that starts a firmware update. Defaults
+    /// to `firmwareUpdate`.
+    #[must_use]
+    pub fn command(mut self, name: impl Into<String>) -> Self {
+        self.command = name.into();
+        self
+    }
+
+    /// Sets the stack size of the thread that runs updates. Defaults to 8KB.
+    #[inline]
+    #[must_use]
+    pub const fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// If set `false`, the running image is not confirmed when the device
+    /// connects, and must be confirmed by the application. Defaults to
+    /// `true`.
+    #[inline]
+    #[must_use]
+    pub const fn confirm_on_connect(mut self, confirm: bool) -> Self {
+        self.confirm_on_connect = confirm;
+        self
+    }
+
+    /// Download, verify, and write the image in `update`, passing progress to
+    /// `report`. Returns `false` if the requested version is already running.
+    /// Restarting into the new image is left to the caller.
+    ///
+    /// # Errors
+    ///
+    /// - if the URL is not HTTPS or the digest is not 64 hex digits
+    /// - if the image could not be downloaded or written
+    /// - if the digest of the image did not match
+    pub fn update(
+        &mut self,
+        update: &FirmwareUpdate,
+        mut report: impl FnMut(&OtaReport),
+    ) -> Result<bool> {
+        let mut status = |ota_status, ota_progress, ota_error| {
+            report(&OtaReport {
+                ota_status,
+                ota_version: update.version.clone(),
+                ota_progress,
+                ota_error,
+            });
+        };
+
+        if update.version == self.version {
+            status(OtaStatus::Current, None, None);
+            return Ok(false);
+        }
+
+        match self.write(update, &mut status) {
+            Ok(()) => {
+                status(OtaStatus::Updated, Some(100), None);
+                Ok(true)
+            }
+            Err(e) => {
+                status(OtaStatus::Failed, None, Some(e.to_string()));
+                Err(e)
+            }
+        }
+    }
+
+    /// Mark the running image as valid, and report it.
+    ///
+    /// # Errors
+    ///
+    /// - if the running image could not be marked valid
+    pub fn confirm(&mut self, mut report: impl FnMut(&OtaReport)) -> Result<()> {
+        self.firmware.confirm()?;
+        report(&OtaReport {
+            ota_status: OtaStatus::Confirmed,
+            ota_version: self.version.clone(),
+            ota_progress: None,
+            ota_error: None,
+        });
+
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        update: &FirmwareUpdate,
+        status: &mut impl FnMut(OtaStatus, Option<u8>, Option<String>),
+    ) -> Result<()> {
+        if !update.url.starts_with("https://") {
+            return Err(Error::InvalidUpdate("the firmware URL must be HTTPS"));
+        }
+        let expected = parse_sha256(&update.sha256).ok_or(Error::InvalidUpdate(
+            "the SHA-256 digest must be 64 hex digits",
+        ))?;
+
+        status(OtaStatus::Downloading, Some(0), None);
+        let len = self.download.open(&update.url)?;
+        let mut writer = self.firmware.begin()?;
+        match copy(&mut self.download, &mut writer, len, status) {
+            Ok(digest) if digest == expected => writer.complete(),
+            Ok(_) => {
+                writer.abort().ok();
+                Err(Error::Checksum)
+            }
+            Err(e) => {
+                writer.abort().ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// Start the thread that runs updates, publishing reports to
+    /// `state_topic`.
+    pub(crate) fn start(self, client: WeakClient, state_topic: String) -> Result<Handle>
+    where
+        F: Send + 'static,
+        D: Send + 'static,
+    {
+        let (jobs, rx) = mpsc::channel();
+        let command = self.command.clone();
+        let stack_size = self.stack_size;
+        thread::Builder::new()
+            .name("losant-ota".to_owned())
+            .stack_size(stack_size)
+            .spawn(move || self.run(rx.iter(), &client, &state_topic))?;
+
+        Ok(Handle { command, jobs })
+    }
+
+    fn run(mut self, jobs: impl Iterator<Item = Job>, client: &WeakClient, state_topic: &str) {
+        let report = |report: &OtaReport| {
+            let state = State {
+                data: report,
+                time: None,
+                flow_version: None,
+                meta: None,
+            };
+            if let Ok(payload) = serde_json::to_vec(&state) {
+                client.defer(Deferred {
+                    topic: state_topic.to_owned(),
+                    qos: QoS::AtLeastOnce,
+                    retain: false,
+                    payload,
+                });
+            }
+        };
+
+        let mut confirmed = !self.confirm_on_connect;
+        for job in jobs {
+            match job {
+                Job::Connected if !confirmed => {
+                    confirmed = self.confirm(report).is_ok();
+                }
+                Job::Connected => {}
+                Job::Update(update) => {
+                    if let Ok(true) = self.update(&update, report) {
+                        thread::sleep(RESTART_DELAY);
+                        self.firmware.restart();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Starts the update thread of a type-erased `Ota`.
+pub(crate) type Start = Box<dyn FnOnce(WeakClient, String) -> Result<Handle>>;
+
+/// Work for the update thread.
+pub(crate) enum Job {
+    Connected,
+    Update(FirmwareUpdate),
+}
+
+/// A handle to the update thread, which stops once every handle is dropped.
+pub(crate) struct Handle {
+    command: String,
+    jobs: Sender<Job>,
+}
+
+impl Handle {
+    /// The name of the command that starts an update.
+    pub(crate) fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Tell the update thread that the device connected.
+    pub(crate) fn connected(&self) {
+        self.jobs.send(Job::Connected).ok();
+    }
+
+    /// The command handler that passes updates to the update thread.
+    pub(crate) fn route(&self) -> Route {
+        let jobs = self.jobs.clone();
+        Box::new(move |command: &Value| {
+            if let Ok(update) = serde::Deserialize::deserialize(&command["payload"]) {
+                jobs.send(Job::Update(update)).ok();
+            }
+        })
+    }
+}
+
+/// Copy the image from `download` to `writer`, reporting progress. Returns the
+/// digest of the image.
+fn copy(
+    download: &mut impl Download,
+    writer: &mut impl FirmwareWriter,
+    len: Option<usize>,
+    status: &mut impl FnMut(OtaStatus, Option<u8>, Option<String>),
+) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut written = 0;
+    let mut reported = 0;
+
+    loop {
+        let n = download.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+        writer.write(&buf[..n])?;
+        written += n;
+
+        // report every 10%
+        if let Some(len) = len.filter(|&len| len > 0) {
+            let progress = u8::try_from(written.saturating_mul(100) / len).unwrap_or(100);
+            if progress / 10 > reported / 10 {
+                reported = progress;
+                status(OtaStatus::Downloading, Some(progress.min(100)), None);
+            }
+        }
+    }
+
+    status(OtaStatus::Verifying, None, None);
+    Ok(hasher.finalize().into())
+}
+
+/// Parse a hex encoded SHA-256 digest.
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(digest)
+}
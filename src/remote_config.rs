@@ -0,0 +1,266 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use embedded_svc::mqtt::client::QoS;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::command::Route;
+use crate::shared::{Deferred, WeakClient};
+use crate::{Result, State, Storage};
+
+pub trait ConfigValidator<T> =
+    for<'b> FnMut(&'b T) -> std::result::Result<(), String> + Send + 'static;
+
+/// A configuration with the version it was applied at, as sent in the payload
+/// of the update command and as persisted.
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    version: u64,
+    config: T,
+}
+
+/// The reported state, e.g.
+/// `{ "data": { "interval": 60, "configVersion": 3 } }`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report<'a, T> {
+    #[serde(flatten)]
+    config: &'a T,
+    config_version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_error: Option<&'a str>,
+}
+
+struct Current<T> {
+    config: T,
+    version: u64,
+}
+
+struct Hooks<T> {
+    storage: Option<Box<dyn Storage + Send>>,
+    key: String,
+    command: String,
+    validate: Option<Box<dyn ConfigValidator<T>>>,
+    on_change: Option<Box<dyn FnMut(&T) + Send>>,
+}
+
+struct Shared<T> {
+    current: Mutex<Current<T>>,
+    /// Held while applying an update, so that `get()` is never blocked by the
+    /// callbacks.
+    hooks: Mutex<Hooks<T>>,
+}
+
+/// A typed device configuration that Losant can update with a command (see
+/// `command()`) with a payload of `{ "version": 3, "config": { ... } }`.
+///
+/// An update is applied if its version is newer than the current one and
+/// `validate()` accepts it. It is then persisted to `storage()`, if set, so
+/// it is restored on the next boot. The applied configuration is reported
+/// back as state, flattened into `data` with a `configVersion` attribute, and
+/// a `configError` attribute if an update was rejected. It is also reported
+/// every time the device connects.
+///
+/// `RemoteConfig` is a cheap handle; clone it to read the configuration from
+/// elsewhere.
+pub struct RemoteConfig<T>(Arc<Shared<T>>);
+
+impl<T> Clone for RemoteConfig<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> RemoteConfig<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + 'static,
+{
+    /// Create a `RemoteConfig` with `config` at version 0, which is used
+    /// until an update is applied or loaded from storage.
+    #[must_use]
+    pub fn new(config: T) -> Self {
+        Self(Arc::new(Shared {
+            current: Mutex::new(Current { config, version: 0 }),
+            hooks: Mutex::new(Hooks {
+                storage: None,
+                key: "losant_config".to_owned(),
+                command: "updateConfig".to_owned(),
+                validate: None,
+                on_change: None,
+            }),
+        }))
+    }
+
+    fn current(&self) -> MutexGuard<'_, Current<T>> {
+        self.0
+            .current
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn hooks(&self) -> MutexGuard<'_, Hooks<T>> {
+        self.0.hooks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sets the storage that the configuration is persisted to. It is loaded
+    /// when the device is built.
+    #[must_use]
+    pub fn storage(self, storage: impl Storage + Send + 'static) -> Self {
+        self.hooks().storage = Some(Box::new(storage));
+        self
+    }
+
+    /// Sets the storage key. Defaults to `losant_config`.
+    #[must_use]
+    pub fn key(self, key: impl Into<String>) -> Self {
+        self.hooks().key = key.into();
+        self
+    }
+
+    /// Sets the name of the command that updates the configuration. Defaults
+    /// to `updateConfig`.
+    #[must_use]
+    pub fn command(self, name: impl Into<String>) -> Self {
+        self.hooks().command = name.into();
+        self
+    }
+
+    /// Sets a check for updates, which rejects an update by returning the
+    /// reason.
+    #[must_use]
+    pub fn validate(self, validate: impl ConfigValidator<T>) -> Self {
+        self.hooks().validate = Some(Box::new(validate));
+        self
+    }
+
+    /// Sets a handler that is called with the configuration after an update
+    /// is applied.
+    #[must_use]
+    pub fn on_change(self, handler: impl FnMut(&T) + Send + 'static) -> Self {
+        self.hooks().on_change = Some(Box::new(handler));
+        self
+    }
+
+    /// The current configuration.
+    #[must_use]
+    pub fn get(&self) -> T {
+        self.current().config.clone()
+    }
+
+    /// The version of the current configuration.
+    #[must_use]
+    pub fn version(&self) -> u64 {
+        self.current().version
+    }
+
+    /// Load the persisted configuration, if any, and return the handlers for
+    /// the update command and for connecting.
+    pub(crate) fn start(self, client: WeakClient, state_topic: String) -> Result<Attached> {
+        let mut hooks = self.hooks();
+        let key = hooks.key.clone();
+        let stored = match hooks.storage.as_mut() {
+            Some(storage) => storage.load(&key)?,
+            None => None,
+        };
+        if let Some(Ok(Versioned { version, config })) =
+            stored.map(|stored| serde_json::from_slice(&stored))
+        {
+            *self.current() = Current { config, version };
+        }
+        let command = hooks.command.clone();
+        drop(hooks);
+
+        let report = {
+            let config = self.clone();
+            let client = client.clone();
+            let state_topic = state_topic.clone();
+            move || config.report(&client, &state_topic, None)
+        };
+        let route = Box::new(move |command: &Value| {
+            let applied = self.apply(&command["payload"]);
+            self.report(&client, &state_topic, applied.err().as_deref());
+        });
+
+        Ok(Attached {
+            command,
+            route,
+            report: Box::new(report),
+        })
+    }
+
+    /// Validate, persist, and apply an update.
+    fn apply(&self, payload: &Value) -> std::result::Result<(), String> {
+        let update = Versioned::<T>::deserialize(payload).map_err(|e| e.to_string())?;
+        let mut hooks = self.hooks();
+        let version = self.version();
+        if update.version == version {
+            return Ok(());
+        }
+        if update.version < version {
+            return Err(format!(
+                "version {} is older than the current version {version}",
+                update.version
+            ));
+        }
+
+        if let Some(validate) = &mut hooks.validate {
+            validate(&update.config)?;
+        }
+
+        let key = hooks.key.clone();
+        if let Some(storage) = &mut hooks.storage {
+            let stored = serde_json::to_vec(&update).map_err(|e| e.to_string())?;
+            storage.store(&key, &stored).map_err(|e| e.to_string())?;
+        }
+
+        *self.current() = Current {
+            config: update.config.clone(),
+            version: update.version,
+        };
+        if let Some(on_change) = &mut hooks.on_change {
+            on_change(&update.config);
+        }
+
+        Ok(())
+    }
+
+    /// Publish the current configuration as state.
+    fn report(&self, client: &WeakClient, state_topic: &str, error: Option<&str>) {
+        let current = self.current();
+        let state = State {
+            data: Report {
+                config: &current.config,
+                config_version: current.version,
+                config_error: error,
+            },
+            time: None,
+            flow_version: None,
+            meta: None,
+        };
+        let payload = serde_json::to_vec(&state);
+        drop(current);
+
+        if let Ok(payload) = payload {
+            client.defer(Deferred {
+                topic: state_topic.to_owned(),
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                payload,
+            });
+        }
+    }
+}
+
+/// The handlers of a started `RemoteConfig`.
+pub(crate) struct Attached {
+    /// The name of the update command.
+    pub(crate) command: String,
+    /// The handler for the update command.
+    pub(crate) route: Route,
+    /// Reports the configuration, e.g. on connect.
+    pub(crate) report: Box<dyn FnMut() + Send>,
+}
+
+/// Starts a type-erased `RemoteConfig`.
+pub(crate) type Start = Box<dyn FnOnce(WeakClient, String) -> Result<Attached>>;
@@ -0,0 +1,384 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::{Result, Storage};
+
+const STORAGE_KEY: &str = "losant_sched";
+/// How long to wait when nothing is scheduled.
+const IDLE: Duration = Duration::from_secs(3600);
+
+/// Dispatches a command to the device's command handlers.
+pub(crate) type Dispatch = Box<dyn FnMut(&Value) + Send>;
+
+/// Holds Losant commands that ask to be run later, and dispatches them to the
+/// command handler at the right moment.
+///
+/// A command is scheduled if its payload has any of these fields:
+///
+/// - `delay`: milliseconds to wait before running the command
+/// - `time`: when to run the command, in milliseconds since the Unix epoch
+/// - `interval`: milliseconds between runs of a recurring command, starting
+///   after `delay`, at `time`, or after one `interval`
+/// - `scheduleId`: an ID for the scheduled command, which replaces any
+///   scheduled command with the same ID. Recurring commands without one use
+///   the command name.
+///
+/// e.g. `{ "name": "setLed", "payload": { "delay": 600000, "ledR": 0, ... } }`
+///
+/// A command named `cancelCommand` (see `cancel_command()`) with a payload of
+/// `{ "scheduleId": "..." }` cancels the scheduled command with that ID.
+///
+/// All commands, scheduled or not, are dispatched from a separate thread, so
+/// command handlers never run in the MQTT event handler and may block. If
+/// `storage()` is set, scheduled commands are persisted across reboots, and
+/// runs of recurring commands missed while powered off are skipped. Wall
+/// clock times require the system time to be set, e.g. with SNTP, but delays
+/// and intervals do not.
+pub struct Scheduler {
+    storage: Option<Box<dyn Storage + Send>>,
+    cancel_command: String,
+    stack_size: usize,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    /// Create a `Scheduler` that does not persist scheduled commands.
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            storage: None,
+            cancel_command: "cancelCommand".to_owned(),
+            stack_size: 8192,
+        }
+    }
+
+    /// Sets the storage that scheduled commands are persisted to.
+    #[must_use]
+    pub fn storage(mut self, storage: impl Storage + Send + 'static) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
+    /// Sets the name of the command that cancels a scheduled command.
+    /// Defaults to `cancelCommand`.
+    #[must_use]
+    pub fn cancel_command(mut self, name: impl Into<String>) -> Self {
+        self.cancel_command = name.into();
+        self
+    }
+
+    /// Sets the stack size of the thread that runs scheduled commands.
+    /// Defaults to 8KB.
+    #[inline]
+    #[must_use]
+    pub const fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Load persisted commands and start dispatching scheduled commands.
+    pub(crate) fn start(mut self, dispatch: Dispatch) -> Result<Worker> {
+        let mut entries: Vec<Entry> = match self.storage.as_mut().map(|s| s.load(STORAGE_KEY)) {
+            Some(Ok(Some(entries))) => serde_json::from_slice(&entries).unwrap_or_default(),
+            Some(Err(e)) => return Err(e),
+            _ => Vec::new(),
+        };
+        // recurring entries are persisted with the due time they were
+        // scheduled with, not every run
+        let now = now_millis();
+        for entry in &mut entries {
+            if entry.interval.is_some() && entry.due <= now {
+                entry.advance(now, Instant::now());
+            }
+        }
+
+        let schedule = Schedule(Arc::new(Shared {
+            state: Mutex::new(State {
+                entries,
+                now: VecDeque::new(),
+                dirty: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+            cancel_command: self.cancel_command,
+        }));
+        let thread = thread::Builder::new()
+            .name("losant-scheduler".to_owned())
+            .stack_size(self.stack_size)
+            .spawn({
+                let schedule = schedule.clone();
+                move || schedule.run(self.storage, dispatch)
+            })?;
+
+        Ok(Worker {
+            schedule,
+            thread: Some(thread),
+        })
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// When the command is due, in milliseconds since the Unix epoch.
+    due: u64,
+    /// When the command is due, if it was scheduled relative to now, so that
+    /// setting the system time doesn't move it.
+    #[serde(skip)]
+    at: Option<Instant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    command: Value,
+}
+
+impl Entry {
+    /// How long until the command is due, or zero if it is.
+    fn remaining(&self, now: u64, instant: Instant) -> Duration {
+        match self.at {
+            Some(at) => at.saturating_duration_since(instant),
+            None => Duration::from_millis(self.due.saturating_sub(now)),
+        }
+    }
+
+    /// Update the wall clock due time of a command scheduled relative to
+    /// now, e.g. after the system time is set.
+    fn sync(&mut self, now: u64, instant: Instant) {
+        if self.at.is_some() {
+            let remaining = self.remaining(now, instant).as_millis();
+            self.due = now.saturating_add(u64::try_from(remaining).unwrap_or(u64::MAX));
+        }
+    }
+
+    /// Move a recurring command to its next run after now, skipping missed
+    /// runs, e.g. while powered off.
+    fn advance(&mut self, now: u64, instant: Instant) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        // the milliseconds to skip in one step, at least one interval
+        let skip = |late: u64| (late / interval).saturating_add(1).saturating_mul(interval);
+
+        match self.at {
+            Some(at) => {
+                let late = instant.saturating_duration_since(at).as_millis();
+                let late = u64::try_from(late).unwrap_or(u64::MAX);
+                match at.checked_add(Duration::from_millis(skip(late))) {
+                    Some(next) => {
+                        self.at = Some(next);
+                        self.sync(now, instant);
+                    }
+                    None => {
+                        self.at = None;
+                        self.due = u64::MAX;
+                    }
+                }
+            }
+            None => self.due = self.due.saturating_add(skip(now.saturating_sub(self.due))),
+        }
+    }
+}
+
+struct State {
+    entries: Vec<Entry>,
+    /// Commands to dispatch as soon as possible, in the order received.
+    now: VecDeque<Value>,
+    dirty: bool,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    cancel_command: String,
+}
+
+/// Scheduled commands, shared between the scheduler thread and the MQTT event
+/// handler. The scheduler thread owns the command handlers, and never holds the
+/// lock while running them, so the event handler only ever waits briefly.
+#[derive(Clone)]
+pub(crate) struct Schedule(Arc<Shared>);
+
+impl Schedule {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Schedule or cancel `command` if it asks for it, otherwise queue it to
+    /// be dispatched now.
+    pub(crate) fn submit(&self, command: Value) {
+        if !self.intercept(&command) {
+            self.state().now.push_back(command);
+            self.0.changed.notify_all();
+        }
+    }
+
+    /// Schedule or cancel `command` if it asks for it. Returns `false` if the
+    /// command should be dispatched now.
+    fn intercept(&self, command: &Value) -> bool {
+        let payload = &command["payload"];
+        let interval = payload["interval"].as_u64().filter(|&i| i > 0);
+        let id = payload["scheduleId"]
+            .as_str()
+            .map(str::to_owned)
+            .or_else(|| {
+                // a recurring command must be cancellable, and not be scheduled
+                // again every time it is sent
+                interval.and_then(|_| command["name"].as_str().map(str::to_owned))
+            });
+
+        if command["name"].as_str() == Some(self.0.cancel_command.as_str()) {
+            if let Some(id) = id {
+                let mut state = self.state();
+                state.entries.retain(|entry| entry.id.as_ref() != Some(&id));
+                state.dirty = true;
+                drop(state);
+                self.0.changed.notify_all();
+            }
+
+            return true;
+        }
+
+        let now = now_millis();
+        let instant = Instant::now();
+        let (due, at) = match (payload["delay"].as_u64(), payload["time"].as_u64()) {
+            (Some(delay), _) => (
+                now.saturating_add(delay),
+                instant.checked_add(Duration::from_millis(delay)),
+            ),
+            (None, Some(time)) => (time, None),
+            (None, None) => match interval {
+                Some(interval) => (
+                    now.saturating_add(interval),
+                    instant.checked_add(Duration::from_millis(interval)),
+                ),
+                None => return false,
+            },
+        };
+        if due <= now && interval.is_none() {
+            return false;
+        }
+
+        let mut state = self.state();
+        if id.is_some() {
+            state.entries.retain(|entry| entry.id != id);
+        }
+        state.entries.push(Entry {
+            id,
+            due,
+            at,
+            interval,
+            command: command.clone(),
+        });
+        state.dirty = true;
+        drop(state);
+        self.0.changed.notify_all();
+
+        true
+    }
+
+    /// Dispatch commands as they become due until stopped.
+    fn run(&self, mut storage: Option<Box<dyn Storage + Send>>, mut dispatch: Dispatch) {
+        let mut state = self.state();
+        while !state.stopped {
+            if let Some(command) = state.now.pop_front() {
+                drop(state);
+                dispatch(&command);
+                state = self.state();
+                continue;
+            }
+
+            let now = now_millis();
+            let instant = Instant::now();
+            if state.dirty {
+                state.dirty = false;
+                for entry in &mut state.entries {
+                    entry.sync(now, instant);
+                }
+                let entries = serde_json::to_vec(&state.entries);
+                drop(state);
+                if let (Some(storage), Ok(entries)) = (storage.as_mut(), entries) {
+                    storage.store(STORAGE_KEY, &entries).ok();
+                }
+
+                state = self.state();
+                continue;
+            }
+
+            if let Some(i) = state
+                .entries
+                .iter()
+                .position(|entry| entry.remaining(now, instant).is_zero())
+            {
+                let command = if state.entries[i].interval.is_some() {
+                    // not persisted, as the next run follows from the first
+                    let entry = &mut state.entries[i];
+                    entry.advance(now, instant);
+                    entry.command.clone()
+                } else {
+                    state.dirty = true;
+                    state.entries.remove(i).command
+                };
+                drop(state);
+
+                dispatch(&command);
+
+                state = self.state();
+                continue;
+            }
+
+            let wait = state
+                .entries
+                .iter()
+                .map(|entry| entry.remaining(now, instant))
+                .min()
+                .unwrap_or(IDLE);
+            state = self
+                .0
+                .changed
+                .wait_timeout(state, wait)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+/// The scheduler thread, stopped when dropped.
+pub(crate) struct Worker {
+    schedule: Schedule,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub(crate) const fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.schedule.state().stopped = true;
+        self.schedule.0.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// The system time in milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| u64::try_from(now.as_millis()).unwrap_or(u64::MAX))
+}
@@ -1,9 +1,333 @@
-// use serde_json::json;
+use std::io;
 
-// pub fn serialize() {
+use ::serde::ser::{self, Impossible};
 
-// }
+use crate::codec::Codec;
+use crate::{Error, Result, MAX_PAYLOAD_SIZE};
 
-// pub fn deserialize() {
+/// Serialize `value` as JSON into `buf`, replacing its contents. `buf` is
+/// never grown past `limit` bytes, so its allocation can be reused between
+/// messages without fragmenting the heap.
+///
+/// # Errors
+///
+/// - if `value` is larger than `limit` (see `size_error()`)
+/// - if there was an error serializing `value`
+pub(crate) fn to_json_buf<T>(buf: &mut Vec<u8>, limit: usize, value: &T) -> Result<()>
+where
+    T: ?Sized + ::serde::Serialize,
+{
+    let mut capped = Capped::new(buf, limit);
+    serde_json::to_writer(&mut capped, value).map_err(|e| {
+        if capped.overflowed {
+            size_error(limit)
+        } else {
+            Error::Json(e)
+        }
+    })
+}
 
-// }
+/// Encode `value` with `codec` into `buf`, replacing its contents. Like
+/// `to_json_buf()`, `buf` is never grown past `limit` bytes.
+///
+/// # Errors
+///
+/// - if `value` is larger than `limit` (see `size_error()`)
+/// - if there was an error serializing `value`
+pub(crate) fn encode_buf<C, T>(codec: &C, buf: &mut Vec<u8>, limit: usize, value: &T) -> Result<()>
+where
+    C: Codec,
+    T: ?Sized + ::serde::Serialize,
+{
+    let mut capped = Capped::new(buf, limit);
+    codec.encode(value, &mut capped).map_err(|e| {
+        if capped.overflowed {
+            size_error(limit)
+        } else {
+            e
+        }
+    })
+}
+
+/// The error for a payload that doesn't fit in `limit` bytes:
+/// `Error::PayloadSize` if `limit` is `MAX_PAYLOAD_SIZE`, otherwise
+/// `Error::BufferSize`.
+pub(crate) const fn size_error(limit: usize) -> Error {
+    if limit >= MAX_PAYLOAD_SIZE {
+        Error::PayloadSize
+    } else {
+        Error::BufferSize
+    }
+}
+
+/// A writer that fails instead of growing a buffer past `limit` bytes.
+struct Capped<'a> {
+    buf: &'a mut Vec<u8>,
+    limit: usize,
+    /// Whether a write failed because of the limit, as serializers wrap the
+    /// writer's error in their own.
+    overflowed: bool,
+}
+
+impl<'a> Capped<'a> {
+    /// Clear `buf` and write to it from the start.
+    fn new(buf: &'a mut Vec<u8>, limit: usize) -> Self {
+        buf.clear();
+        Self {
+            buf,
+            limit,
+            overflowed: false,
+        }
+    }
+}
+
+impl io::Write for Capped<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            self.overflowed = true;
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serialize the elements of `value` one at a time into `buf`, replacing its
+/// contents, and pass each to `element`, so that an array too large for one
+/// message is never held in memory as a whole, e.g. as a `serde_json::Value`.
+/// Each element is limited to `limit` bytes. Returns `false` without calling
+/// `element` if `value` does not serialize as a sequence.
+///
+/// # Errors
+///
+/// - if an element is larger than `limit` (see `size_error()`)
+/// - if there was an error serializing an element
+/// - if `element` returned an error
+pub(crate) fn for_each_element<T, F>(
+    value: &T,
+    buf: &mut Vec<u8>,
+    limit: usize,
+    element: F,
+) -> Result<bool>
+where
+    T: ?Sized + ::serde::Serialize,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    let mut elements = Elements {
+        buf,
+        limit,
+        element,
+        sequence: false,
+        error: None,
+    };
+    match value.serialize(&mut elements) {
+        Ok(()) => Ok(true),
+        Err(_) if !elements.sequence => Ok(false),
+        Err(e) => Err(elements.error.unwrap_or(Error::Json(e))),
+    }
+}
+
+/// A serializer that accepts only a sequence, and serializes its elements
+/// separately, see `for_each_element()`.
+struct Elements<'a, F> {
+    buf: &'a mut Vec<u8>,
+    limit: usize,
+    element: F,
+    /// Whether the value is a sequence.
+    sequence: bool,
+    /// Why an element failed, as serializers only return their own errors.
+    error: Option<Error>,
+}
+
+impl<F> Elements<'_, F>
+where
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    fn element<T>(&mut self, value: &T) -> std::result::Result<(), serde_json::Error>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        to_json_buf(self.buf, self.limit, value)
+            .and_then(|()| (self.element)(self.buf))
+            .map_err(|e| {
+                self.error = Some(e);
+                ser::Error::custom("element failed")
+            })
+    }
+}
+
+fn not_a_sequence() -> serde_json::Error {
+    ser::Error::custom("not a sequence")
+}
+
+/// Reject every value but a sequence.
+macro_rules! not_a_sequence {
+    ($($method:ident($($arg:ty),*);)*) => {$(
+        fn $method(self, $(_: $arg),*) -> std::result::Result<(), serde_json::Error> {
+            Err(not_a_sequence())
+        }
+    )*};
+}
+
+impl<F> ser::Serializer for &mut Elements<'_, F>
+where
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    type Ok = ();
+    type Error = serde_json::Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Impossible<(), serde_json::Error>;
+    type SerializeTupleVariant = Impossible<(), serde_json::Error>;
+    type SerializeMap = Impossible<(), serde_json::Error>;
+    type SerializeStruct = Impossible<(), serde_json::Error>;
+    type SerializeStructVariant = Impossible<(), serde_json::Error>;
+
+    not_a_sequence! {
+        serialize_bool(bool);
+        serialize_i8(i8);
+        serialize_i16(i16);
+        serialize_i32(i32);
+        serialize_i64(i64);
+        serialize_u8(u8);
+        serialize_u16(u16);
+        serialize_u32(u32);
+        serialize_u64(u64);
+        serialize_f32(f32);
+        serialize_f64(f64);
+        serialize_char(char);
+        serialize_str(&str);
+        serialize_bytes(&[u8]);
+        serialize_none();
+        serialize_unit();
+        serialize_unit_struct(&'static str);
+        serialize_unit_variant(&'static str, u32, &'static str);
+    }
+
+    fn serialize_some<T>(self, value: &T) -> std::result::Result<(), serde_json::Error>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> std::result::Result<(), serde_json::Error>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> std::result::Result<(), serde_json::Error>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        Err(not_a_sequence())
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> std::result::Result<Self, serde_json::Error> {
+        self.sequence = true;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> std::result::Result<Self, serde_json::Error> {
+        self.sequence = true;
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self::SerializeTupleStruct, serde_json::Error> {
+        Err(not_a_sequence())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self::SerializeTupleVariant, serde_json::Error> {
+        Err(not_a_sequence())
+    }
+
+    fn serialize_map(
+        self,
+        _: Option<usize>,
+    ) -> std::result::Result<Self::SerializeMap, serde_json::Error> {
+        Err(not_a_sequence())
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self::SerializeStruct, serde_json::Error> {
+        Err(not_a_sequence())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> std::result::Result<Self::SerializeStructVariant, serde_json::Error> {
+        Err(not_a_sequence())
+    }
+}
+
+impl<F> ser::SerializeSeq for &mut Elements<'_, F>
+where
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> std::result::Result<(), serde_json::Error>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> std::result::Result<(), serde_json::Error> {
+        Ok(())
+    }
+}
+
+impl<F> ser::SerializeTuple for &mut Elements<'_, F>
+where
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    type Ok = ();
+    type Error = serde_json::Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> std::result::Result<(), serde_json::Error>
+    where
+        T: ?Sized + ::serde::Serialize,
+    {
+        self.element(value)
+    }
+
+    fn end(self) -> std::result::Result<(), serde_json::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError, Weak};
+use std::time::Instant;
+
+use embedded_svc::mqtt::client::QoS;
+use esp_idf_svc::mqtt::client::EspMqttClient;
+
+use crate::ack::Inflight;
+use crate::metrics::Counters;
+use crate::{Error, Operation};
+
+/// The most messages kept waiting for the client. The oldest are dropped
+/// first, e.g. while a transient error keeps them from being enqueued.
+const MAX_DEFERRED: usize = 32;
+/// How many times a message is enqueued before it is dropped.
+const MAX_ATTEMPTS: u8 = 3;
+
+/// A message waiting for the shared client to become free.
+pub(crate) struct Deferred {
+    pub(crate) topic: String,
+    pub(crate) qos: QoS,
+    pub(crate) retain: bool,
+    pub(crate) payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Inner {
+    client: Mutex<Option<EspMqttClient>>,
+    /// Messages with the number of times they failed to be enqueued.
+    deferred: Mutex<VecDeque<(Deferred, u8)>>,
+    closed: AtomicBool,
+    /// Tracks deferred QoS 1 messages once enqueued, like the `Device` does
+    /// for its own.
+    inflight: Inflight,
+    metrics: Counters,
+}
+
+impl Inner {
+    fn deferred(&self) -> MutexGuard<'_, VecDeque<(Deferred, u8)>> {
+        self.deferred.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn defer(&self, message: Deferred) {
+        if self.closed.load(Ordering::Acquire) {
+            return;
+        }
+
+        let mut deferred = self.deferred();
+        if deferred.len() >= MAX_DEFERRED {
+            deferred.pop_front();
+        }
+        deferred.push_back((message, 0));
+        drop(deferred);
+        self.try_flush();
+    }
+
+    /// Enqueue all deferred messages with the locked client. Returns `false`
+    /// if some are left to retry later, because the client is not set yet or
+    /// a message failed with a transient error. Messages that fail with a
+    /// permanent error, or `MAX_ATTEMPTS` times, are dropped.
+    fn flush(&self, client: &mut Option<EspMqttClient>) -> bool {
+        let Some(client) = client.as_mut() else {
+            return false;
+        };
+
+        loop {
+            // the deferred lock must not be held while calling into the client
+            let next = self.deferred().pop_front();
+            let Some((message, failures)) = next else {
+                return true;
+            };
+
+            let started = Instant::now();
+            let enqueued = client
+                .enqueue(
+                    &message.topic,
+                    message.qos,
+                    message.retain,
+                    &message.payload,
+                )
+                .map_err(Error::client(Operation::Enqueue, &message.topic));
+            match enqueued {
+                Ok(id) => {
+                    self.metrics
+                        .published(message.payload.len(), started.elapsed());
+                    self.inflight.track(message.qos, id);
+                }
+                Err(e) if e.is_transient() && failures + 1 < MAX_ATTEMPTS => {
+                    self.deferred().push_front((message, failures + 1));
+                    return false;
+                }
+                Err(e) => {
+                    self.metrics.failed(e);
+                }
+            }
+        }
+    }
+
+    /// Flush deferred messages if the client is free. If it is not, whoever
+    /// holds it flushes them when it is released.
+    fn try_flush(&self) {
+        loop {
+            let mut client = match self.client.try_lock() {
+                Ok(client) => client,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => return,
+            };
+            let flushed = self.flush(&mut client);
+            drop(client);
+
+            // a message may have been deferred after flushing, but before the
+            // client was released
+            if !flushed || self.deferred().is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+/// The MQTT client, shared between a `Device` and code that publishes from
+/// the MQTT event handler or other threads.
+///
+/// The event handler runs while ESP-IDF holds the client's internal lock, so
+/// it must never block on a `Device` that is waiting for that same lock.
+/// Messages published from there are deferred instead, and enqueued by
+/// whoever holds the client next.
+#[derive(Clone)]
+pub(crate) struct SharedClient(Arc<Inner>);
+
+impl SharedClient {
+    /// Create a `SharedClient` that tracks deferred messages in `inflight`,
+    /// and records them in `metrics`.
+    pub(crate) fn new(inflight: Inflight, metrics: Counters) -> Self {
+        Self(Arc::new(Inner {
+            inflight,
+            metrics,
+            ..Inner::default()
+        }))
+    }
+
+    /// Set the client once it is constructed, and flush any messages that
+    /// were deferred before then.
+    pub(crate) fn set(&self, client: EspMqttClient) {
+        *self.0.client.lock().unwrap_or_else(PoisonError::into_inner) = Some(client);
+        self.0.try_flush();
+    }
+
+    /// Lock the client, blocking until it is free. Must not be called from the
+    /// MQTT event handler.
+    pub(crate) fn lock(&self) -> ClientGuard<'_> {
+        ClientGuard {
+            inner: &self.0,
+            guard: Some(self.0.client.lock().unwrap_or_else(PoisonError::into_inner)),
+        }
+    }
+
+    /// Enqueue a message without blocking. Dropped if the client is closed.
+    pub(crate) fn defer(&self, message: Deferred) {
+        self.0.defer(message);
+    }
+
+    /// Enqueue the messages deferred so far, blocking until the client is
+    /// free. Must not be called from the MQTT event handler.
+    pub(crate) fn flush(&self) {
+        drop(self.lock());
+    }
+
+    /// Stop accepting deferred messages, e.g. before disconnecting.
+    pub(crate) fn close(&self) {
+        self.0.closed.store(true, Ordering::Release);
+    }
+
+    /// Close the client and take it out, so it is destroyed by the caller
+    /// rather than by whichever thread drops the last handle. ESP-IDF must not
+    /// destroy a client from its own event handler, which may be holding a
+    /// handle upgraded from a `WeakClient`. Must not be called from the MQTT
+    /// event handler.
+    pub(crate) fn take(&self) -> Option<EspMqttClient> {
+        self.close();
+        self.0
+            .client
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    /// A handle for the MQTT event handler, which must not keep the client
+    /// alive.
+    pub(crate) fn downgrade(&self) -> WeakClient {
+        WeakClient(Arc::downgrade(&self.0))
+    }
+}
+
+/// A `SharedClient` handle that does not keep the client alive.
+#[derive(Clone, Default)]
+pub(crate) struct WeakClient(Weak<Inner>);
+
+impl WeakClient {
+    /// Enqueue a message without blocking. Dropped if the client is gone or
+    /// closed.
+    pub(crate) fn defer(&self, message: Deferred) {
+        if let Some(inner) = self.0.upgrade() {
+            inner.defer(message);
+        }
+    }
+}
+
+/// Exclusive access to the shared client. Deferred messages are flushed when
+/// the guard is dropped.
+pub(crate) struct ClientGuard<'a> {
+    inner: &'a Inner,
+    guard: Option<MutexGuard<'a, Option<EspMqttClient>>>,
+}
+
+impl Deref for ClientGuard<'_> {
+    type Target = EspMqttClient;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard
+            .as_deref()
+            .and_then(Option::as_ref)
+            .expect("client is set before the device is built")
+    }
+}
+
+impl DerefMut for ClientGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard
+            .as_deref_mut()
+            .and_then(Option::as_mut)
+            .expect("client is set before the device is built")
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        self.inner.try_flush();
+    }
+}
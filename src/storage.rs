@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use embedded_svc::storage::{RawStorage, StorageBase};
+use esp_idf_svc::nvs::{EspNvs, NvsPartitionId};
+
+use crate::Result;
+
+/// Key-value storage that survives deep sleep and reboots, used to retain
+/// state between runs. Keys must be at most 15 characters long to be stored
+/// in NVS.
+pub trait Storage {
+    /// Load the value stored under `key`, if any.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error reading from storage
+    fn load(&mut self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store `value` under `key`, replacing any previous value.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error writing to storage
+    fn store(&mut self, key: &str, value: &[u8]) -> Result<()>;
+
+    /// Remove the value stored under `key`, if any.
+    ///
+    /// # Errors
+    ///
+    /// - if there was an error writing to storage
+    fn remove(&mut self, key: &str) -> Result<()>;
+}
+
+/// Non-volatile storage in an NVS namespace.
+///
+/// See <https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/storage/nvs_flash.html>
+impl<P> Storage for EspNvs<P>
+where
+    P: NvsPartitionId,
+{
+    fn load(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(len) = RawStorage::len(self, key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        let len = self.get_raw(key, &mut buf)?.map(<[u8]>::len);
+        Ok(len.map(|len| {
+            buf.truncate(len);
+            buf
+        }))
+    }
+
+    fn store(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.set_raw(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        StorageBase::remove(self, key)?;
+        Ok(())
+    }
+}
+
+/// Volatile storage, e.g. for state that only needs to survive a reconnect.
+impl Storage for HashMap<String, Vec<u8>> {
+    fn load(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key).cloned())
+    }
+
+    fn store(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.insert(key.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        HashMap::remove(self, key);
+        Ok(())
+    }
+}
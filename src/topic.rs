@@ -0,0 +1,214 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use embedded_svc::mqtt::client::Details;
+
+use crate::{Error, Result, MAX_PAYLOAD_SIZE};
+
+/// The maximum length of an MQTT topic, in bytes.
+const MAX_TOPIC_LEN: usize = 65_535;
+
+pub(crate) type RouteHandler = Box<dyn FnMut(&str, &[u8]) + Send>;
+
+/// Typed subscription handlers keyed by topic filter, shared between a
+/// `Device` and its MQTT event handler.
+#[derive(Clone, Default)]
+pub(crate) struct Routes(Arc<Mutex<Vec<(String, RouteHandler)>>>);
+
+impl Routes {
+    /// Add a handler for messages on topics matching `filter`.
+    pub(crate) fn insert(&self, filter: String, handler: RouteHandler) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((filter, handler));
+    }
+
+    /// Remove all handlers for `filter`.
+    pub(crate) fn remove(&self, filter: &str) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(f, _)| f != filter);
+    }
+
+    /// Whether any handler has a filter matching `topic`.
+    pub(crate) fn contains(&self, topic: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|(filter, _)| matches(filter, topic))
+    }
+
+    /// Pass a received message to every handler with a filter matching
+    /// `topic`. Returns `false` if no filter matched.
+    pub(crate) fn dispatch(&self, topic: &str, data: &[u8]) -> bool {
+        let mut matched = false;
+        for (filter, handler) in self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter_mut()
+        {
+            if matches(filter, topic) {
+                handler(topic, data);
+                matched = true;
+            }
+        }
+
+        matched
+    }
+}
+
+/// A received message fragment, see `Fragments`.
+pub(crate) enum Fragment {
+    /// Part of a message being reassembled, or of one that was dropped.
+    Partial,
+    /// The last part of a reassembled message, with its topic.
+    Complete(String, Vec<u8>),
+    /// Part of a message that is not reassembled.
+    Other,
+}
+
+/// Reassembles messages that ESP-MQTT delivers in fragments because they are
+/// larger than its receive buffer (`MqttClientConfiguration::buffer_size`,
+/// 1KB by default). Fragments of a message arrive in order, and only the first
+/// has a topic.
+#[derive(Default)]
+pub(crate) struct Fragments {
+    current: Option<(String, Vec<u8>)>,
+    /// Whether the fragments of a wanted message are being dropped.
+    dropping: bool,
+}
+
+impl Fragments {
+    /// Add a fragment with `details` of a message, reassembling it if its
+    /// `topic` is `wanted`. Messages larger than `MAX_PAYLOAD_SIZE`, or that
+    /// don't fit on the heap, are dropped.
+    pub(crate) fn receive(
+        &mut self,
+        topic: Option<&str>,
+        details: &Details,
+        data: &[u8],
+        wanted: impl FnOnce(&str) -> bool,
+    ) -> Fragment {
+        match details {
+            Details::Complete => Fragment::Other,
+            Details::InitialChunk(initial) => {
+                self.current = None;
+                self.dropping = false;
+                let Some(topic) = topic.filter(|&topic| wanted(topic)) else {
+                    return Fragment::Other;
+                };
+
+                let mut buf = Vec::new();
+                if initial.total_data_size <= MAX_PAYLOAD_SIZE
+                    && buf.try_reserve_exact(initial.total_data_size).is_ok()
+                {
+                    buf.extend_from_slice(data);
+                    self.current = Some((topic.to_owned(), buf));
+                } else {
+                    self.dropping = true;
+                }
+
+                Fragment::Partial
+            }
+            Details::SubsequentChunk(subsequent) => {
+                let Some((_, buf)) = &mut self.current else {
+                    return if self.dropping {
+                        Fragment::Partial
+                    } else {
+                        Fragment::Other
+                    };
+                };
+                if subsequent.current_data_offset != buf.len()
+                    || buf.len() + data.len() > subsequent.total_data_size
+                {
+                    // a fragment was lost, so the rest of the message is too
+                    self.current = None;
+                    self.dropping = true;
+                    return Fragment::Partial;
+                }
+
+                buf.extend_from_slice(data);
+                if buf.len() < subsequent.total_data_size {
+                    return Fragment::Partial;
+                }
+
+                self.current
+                    .take()
+                    .map_or(Fragment::Partial, |(topic, buf)| {
+                        Fragment::Complete(topic, buf)
+                    })
+            }
+        }
+    }
+}
+
+/// Check that `filter` is a valid MQTT topic filter that a Losant device is
+/// allowed to subscribe to. Topics starting with `losant/` are reserved, except
+/// for those of the device with the topic prefix `own`, e.g. `losant/<id>/`.
+///
+/// See <https://docs.losant.com/mqtt/overview/#custom-mqtt-topics>
+///
+/// # Errors
+///
+/// - if `filter` is not a valid topic filter
+pub(crate) fn validate_filter(filter: &str, own: &str) -> Result<()> {
+    let invalid = |reason| {
+        Err(Error::InvalidTopic {
+            topic: filter.to_owned(),
+            reason,
+        })
+    };
+
+    if filter.is_empty() {
+        return invalid("topic filter is empty");
+    }
+    if filter.len() > MAX_TOPIC_LEN {
+        return invalid("topic filter is longer than 65535 bytes");
+    }
+    if filter.contains('\0') {
+        return invalid("topic filter contains a null character");
+    }
+    if filter.starts_with('$') {
+        return invalid("topics starting with `$` are reserved by the broker");
+    }
+    if filter.starts_with("losant/") && !filter.starts_with(own) {
+        return invalid("topics starting with `losant/` are reserved for other devices");
+    }
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return invalid("`#` must be the last level of a topic filter");
+        }
+        if level.contains('+') && level != "+" {
+            return invalid("`+` must occupy an entire level of a topic filter");
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `topic` matches the topic `filter`, which may contain the MQTT
+/// single-level (`+`) and multi-level (`#`) wildcards.
+///
+/// See <https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106>
+pub(crate) fn matches(filter: &str, topic: &str) -> bool {
+    // wildcards at the first level don't match system topics
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) | (None, None) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            _ => return false,
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use embedded_svc::mqtt::client::QoS;
+
+use crate::client::Client;
+use crate::{Device, Error, Result, MAX_PAYLOAD_SIZE};
+
+const MANIFEST: &str = "manifest";
+const ACK: &str = "ack";
+
+pub trait TransferHandler = for<'b> FnMut(&'b Manifest, &'b [u8]) + Send + 'static;
+
+/// Describes a file sent in chunks, published on `<prefix>/<direction>/<id>/manifest`
+/// before its chunks.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// A unique ID for the transfer.
+    pub id: String,
+    /// The name of the file.
+    pub name: String,
+    /// The size of the file in bytes.
+    pub size: usize,
+    /// The size of every chunk in bytes, except the last.
+    pub chunk_size: usize,
+    /// The number of chunks.
+    pub chunks: usize,
+    /// The CRC-32 (IEEE) checksum of the file.
+    pub crc32: u32,
+}
+
+/// Sent by the receiver on `<prefix>/<direction>/<id>/ack`, with the index of
+/// the next chunk it needs, i.e. the number of chunks received in order.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Ack {
+    next: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Chunked transfers of files that do not fit in one message, on dedicated
+/// topics under a prefix, e.g. `files/<device id>`.
+///
+/// The sender publishes a `Manifest` on `<prefix>/<direction>/<id>/manifest`,
+/// then each chunk as raw bytes on `<prefix>/<direction>/<id>/<index>`, where
+/// the direction is `up` for uploads from the device (see `Device::upload()`)
+/// and `down` for downloads to the device. The receiver acknowledges on
+/// `<prefix>/<direction>/<id>/ack` with `{ "next": <index> }`, the next chunk
+/// it needs, or `{ "next": <index>, "error": "..." }` to reject the transfer.
+///
+/// The sender keeps up to `window()` chunks unacknowledged. If no ack arrives
+/// in time, e.g. after a reconnect, it resends the manifest and resumes from
+/// the chunk the receiver asks for.
+pub struct Transfer {
+    prefix: Option<String>,
+    chunk_size: usize,
+    window: usize,
+    ack_timeout: Duration,
+    retries: u32,
+    max_download_size: usize,
+    handler: Option<Box<dyn TransferHandler>>,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transfer {
+    /// Create a `Transfer` with the topic prefix `files/<device id>`.
+    #[inline]
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            prefix: None,
+            chunk_size: 16_384,
+            window: 4,
+            ack_timeout: Duration::from_secs(10),
+            retries: 3,
+            max_download_size: 65_536,
+            handler: None,
+        }
+    }
+
+    /// Sets the topic prefix. Defaults to `files/<device id>`.
+    #[must_use]
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets the size of uploaded chunks. Values larger than
+    /// `MAX_PAYLOAD_SIZE` are clamped. Defaults to 16KB.
+    #[inline]
+    #[must_use]
+    pub const fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size;
+        self
+    }
+
+    /// Sets the number of uploaded chunks that may be unacknowledged at once.
+    /// Defaults to 4.
+    #[inline]
+    #[must_use]
+    pub const fn window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets how long to wait for an ack before resending. Defaults to 10
+    /// seconds.
+    #[inline]
+    #[must_use]
+    pub const fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// Sets how many times to resend without progress before an upload fails.
+    /// Defaults to 3.
+    #[inline]
+    #[must_use]
+    pub const fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the largest file that may be downloaded. Larger files are
+    /// rejected. Downloads are held in RAM until complete, so this should
+    /// leave room for everything else on the heap. Defaults to 64KB.
+    #[inline]
+    #[must_use]
+    pub const fn max_download_size(mut self, size: usize) -> Self {
+        self.max_download_size = size;
+        self
+    }
+
+    /// Sets the handler for downloaded files, which enables downloads.
+    #[must_use]
+    pub fn on_download(mut self, handler: impl TransferHandler) -> Self {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Split into the upload and download halves for the device `id`.
+    pub(crate) fn split(self, id: &str) -> (Uploads, Option<Downloads>) {
+        let prefix = self.prefix.unwrap_or_else(|| format!("files/{id}"));
+        let uploads = Uploads {
+            topic: format!("{prefix}/up"),
+            chunk_size: self.chunk_size.clamp(1, MAX_PAYLOAD_SIZE),
+            window: self.window.max(1),
+            ack_timeout: self.ack_timeout,
+            retries: self.retries,
+            acks: Acks::default(),
+            count: Arc::default(),
+        };
+        let downloads = self.handler.map(|handler| Downloads {
+            topic: format!("{prefix}/down"),
+            max_size: self.max_download_size,
+            handler,
+            current: None,
+            completed: None,
+        });
+
+        (uploads, downloads)
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    /// The latest ack for each upload, with a sequence number.
+    acks: Mutex<HashMap<String, (u64, Ack)>>,
+    received: Condvar,
+}
+
+/// Acks for uploads, shared between a `Device` and its MQTT event handler.
+#[derive(Clone, Default)]
+struct Acks(Arc<Shared>);
+
+impl Acks {
+    fn acks(&self) -> MutexGuard<'_, HashMap<String, (u64, Ack)>> {
+        self.0.acks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Start recording acks for the upload `id`.
+    fn track(&self, id: &str) {
+        let ack = Ack {
+            next: 0,
+            error: None,
+        };
+        self.acks().insert(id.to_owned(), (0, ack));
+    }
+
+    /// Record an ack, if the upload `id` is in progress.
+    fn insert(&self, id: &str, ack: Ack) {
+        if let Some((seq, latest)) = self.acks().get_mut(id) {
+            *seq += 1;
+            *latest = ack;
+        }
+        self.0.received.notify_all();
+    }
+
+    /// Block until an ack newer than `seq` arrives for `id`, or `timeout`
+    /// elapses.
+    fn wait(&self, id: &str, seq: u64, timeout: Duration) -> Option<(u64, Ack)> {
+        let is_new = |acks: &HashMap<String, (u64, Ack)>| {
+            acks.get(id)
+                .filter(|(received, _)| *received > seq)
+                .cloned()
+        };
+
+        let (acks, _) = self
+            .0
+            .received
+            .wait_timeout_while(self.acks(), timeout, |acks| is_new(acks).is_none())
+            .unwrap_or_else(PoisonError::into_inner);
+
+        is_new(&acks)
+    }
+
+    fn remove(&self, id: &str) {
+        self.acks().remove(id);
+    }
+}
+
+/// The upload half of a `Transfer`.
+#[derive(Clone)]
+pub(crate) struct Uploads {
+    topic: String,
+    chunk_size: usize,
+    window: usize,
+    ack_timeout: Duration,
+    retries: u32,
+    acks: Acks,
+    count: Arc<AtomicU32>,
+}
+
+impl Uploads {
+    /// The topic filter for upload acks.
+    pub(crate) fn ack_filter(&self) -> String {
+        format!("{}/+/{ACK}", self.topic)
+    }
+
+    /// The handler for upload acks.
+    pub(crate) fn ack_handler(&self) -> impl FnMut(&str, &[u8]) + Send + 'static {
+        let acks = self.acks.clone();
+        let topic = format!("{}/", self.topic);
+        move |received: &str, data: &[u8]| {
+            let id = received
+                .strip_prefix(&topic)
+                .and_then(|id| id.strip_suffix(&format!("/{ACK}")));
+            if let (Some(id), Ok(ack)) = (id, serde_json::from_slice(data)) {
+                acks.insert(id, ack);
+            }
+        }
+    }
+
+    /// Send `data` to the receiver in chunks, blocking until every chunk is
+    /// acknowledged.
+    pub(crate) fn upload(&self, device: &mut Device<'_>, name: &str, data: &[u8]) -> Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis());
+        let manifest = Manifest {
+            id: format!("{millis:x}-{}", self.count.fetch_add(1, Ordering::Relaxed)),
+            name: name.to_owned(),
+            size: data.len(),
+            chunk_size: self.chunk_size,
+            chunks: data.len().div_ceil(self.chunk_size),
+            crc32: crc32(0, data),
+        };
+        let topic = format!("{}/{}", self.topic, manifest.id);
+        self.acks.track(&manifest.id);
+        let result = self.send(device, &topic, &manifest, data);
+        self.acks.remove(&manifest.id);
+
+        result
+    }
+
+    fn send(
+        &self,
+        device: &mut Device<'_>,
+        topic: &str,
+        manifest: &Manifest,
+        data: &[u8],
+    ) -> Result<()> {
+        let manifest_topic = format!("{topic}/{MANIFEST}");
+        device.publish(
+            &manifest_topic,
+            QoS::AtLeastOnce,
+            false,
+            &serde_json::to_vec(manifest)?,
+        )?;
+
+        let mut seq = 0;
+        let mut next = 0;
+        let mut sent = 0;
+        let mut retries = 0;
+        while next < manifest.chunks {
+            while sent < manifest.chunks && sent < next + self.window {
+                let start = sent * self.chunk_size;
+                let chunk = &data[start..data.len().min(start + self.chunk_size)];
+                match device.publish(&format!("{topic}/{sent}"), QoS::AtLeastOnce, false, chunk) {
+                    Ok(_) => sent += 1,
+                    // wait for an ack, or resend the manifest and resume
+                    Err(e) if e.is_transient() => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if let Some((received, ack)) = self.acks.wait(&manifest.id, seq, self.ack_timeout) {
+                if ack.error.is_some() {
+                    return Err(Error::Transfer("the transfer was rejected by the receiver"));
+                }
+
+                seq = received;
+                if ack.next > next {
+                    retries = 0;
+                }
+                next = ack.next.min(manifest.chunks);
+                sent = sent.max(next);
+                continue;
+            }
+
+            retries += 1;
+            if retries > self.retries {
+                return Err(Error::Transfer("timed out waiting for an ack"));
+            }
+
+            // the receiver answers the manifest with the chunk to resume from
+            let deadline = Instant::now() + self.ack_timeout;
+            device.wait_connected(self.ack_timeout);
+            let resent = device.publish(
+                &manifest_topic,
+                QoS::AtLeastOnce,
+                false,
+                &serde_json::to_vec(manifest)?,
+            );
+            match resent {
+                Ok(_) => sent = next,
+                Err(e) if e.is_transient() => {
+                    std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A download in progress.
+struct Incoming {
+    manifest: Manifest,
+    data: Vec<u8>,
+    next: usize,
+}
+
+/// The download half of a `Transfer`, run by the MQTT event handler.
+pub(crate) struct Downloads {
+    topic: String,
+    max_size: usize,
+    handler: Box<dyn TransferHandler>,
+    current: Option<Incoming>,
+    /// The last completed download, so a manifest resent because its final
+    /// ack was lost is acked again instead of downloaded again.
+    completed: Option<Manifest>,
+}
+
+impl Downloads {
+    /// The topic filter for manifests and chunks.
+    pub(crate) fn filter(&self) -> String {
+        format!("{}/+/+", self.topic)
+    }
+
+    /// Handle a manifest or chunk. Returns the ack to send, and its topic.
+    pub(crate) fn receive(&mut self, topic: &str, data: &[u8]) -> Option<(String, Vec<u8>)> {
+        let (id, part) = topic
+            .strip_prefix(&self.topic)?
+            .strip_prefix('/')?
+            .split_once('/')?;
+        let ack = if part == MANIFEST {
+            self.start(id, data)
+        } else {
+            self.chunk(id, part.parse().ok()?, data)?
+        };
+
+        let payload = serde_json::to_vec(&ack).ok()?;
+        Some((format!("{}/{id}/{ACK}", self.topic), payload))
+    }
+
+    fn start(&mut self, id: &str, data: &[u8]) -> Ack {
+        let reject = |error: &str| Ack {
+            next: 0,
+            error: Some(error.to_owned()),
+        };
+
+        let Ok(manifest) = serde_json::from_slice::<Manifest>(data) else {
+            return reject("invalid manifest");
+        };
+        if manifest.id != id
+            || manifest.chunk_size == 0
+            || manifest.chunks != manifest.size.div_ceil(manifest.chunk_size)
+        {
+            return reject("invalid manifest");
+        }
+        if manifest.size > self.max_size {
+            return reject("file too large");
+        }
+        if self.completed.as_ref() == Some(&manifest) {
+            return Ack {
+                next: manifest.chunks,
+                error: None,
+            };
+        }
+
+        // a resent manifest resumes the download in progress
+        let current = match self.current.take() {
+            Some(current) if current.manifest == manifest => current,
+            // the buffer grows as chunks arrive, so a manifest alone doesn't
+            // allocate the whole file
+            _ => Incoming {
+                data: Vec::new(),
+                manifest,
+                next: 0,
+            },
+        };
+        let complete = current.next == current.manifest.chunks;
+        self.current = Some(current);
+
+        if complete {
+            self.finish()
+        } else {
+            self.next()
+        }
+    }
+
+    fn chunk(&mut self, id: &str, index: usize, data: &[u8]) -> Option<Ack> {
+        let current = self
+            .current
+            .as_mut()
+            .filter(|current| current.manifest.id == id)?;
+        let manifest = &current.manifest;
+        let expected = manifest
+            .chunk_size
+            .min(manifest.size - current.next * manifest.chunk_size);
+        if index == current.next && data.len() == expected {
+            if current.data.try_reserve_exact(data.len()).is_err() {
+                self.current = None;
+                return Some(Ack {
+                    next: 0,
+                    error: Some("out of memory".to_owned()),
+                });
+            }
+            current.data.extend_from_slice(data);
+            current.next += 1;
+        }
+
+        if current.next < manifest.chunks {
+            return Some(self.next());
+        }
+
+        Some(self.finish())
+    }
+
+    /// Verify the completed download and pass it to the handler.
+    fn finish(&mut self) -> Ack {
+        let Some(current) = self.current.take() else {
+            return self.next();
+        };
+        if crc32(0, &current.data) != current.manifest.crc32 {
+            return Ack {
+                next: 0,
+                error: Some("checksum mismatch".to_owned()),
+            };
+        }
+
+        (self.handler)(&current.manifest, &current.data);
+        let next = current.next;
+        self.completed = Some(current.manifest);
+        Ack { next, error: None }
+    }
+
+    /// Ask for the next chunk of the download in progress.
+    fn next(&self) -> Ack {
+        Ack {
+            next: self.current.as_ref().map_or(0, |current| current.next),
+            error: None,
+        }
+    }
+}
+
+/// Update a CRC-32 (IEEE) checksum with `data`, starting from `0`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}